@@ -0,0 +1,109 @@
+// 比较不同分片数下多线程投递的吞吐量: cargo bench --bench state
+// 每次操作模拟一次 deliver: 发送者限流、去重分配 id, 再按接收者找到连接，一半当作不在线存离线消息，再 pull 和 ack
+extern crate my_chat;
+use my_chat::dedup::SendDedup;
+use my_chat::id::IdAllocator;
+use my_chat::msg::Msg2C;
+use my_chat::ratelimit::RateLimiter;
+use my_chat::shard::Sharded;
+use my_chat::state::ServerState;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const USERS: u64 = 10_000;
+const OPS_PER_THREAD: u64 = 200_000;
+
+// 和服务端一样，这几张表用同样的分片数
+struct Server {
+    ids: Arc<IdAllocator>,
+    recent_sends: Sharded<SendDedup>,
+    rate_limits: Sharded<HashMap<u64, RateLimiter>>,
+    state: ServerState<u64>,
+}
+
+impl Server {
+    fn new(shards: usize) -> Self {
+        let ids = Arc::new(IdAllocator::new(None, false).unwrap());
+        let cursors = Arc::new(IdAllocator::new(None, false).unwrap());
+        let state = ServerState::new(shards, cursors);
+        for user_id in 1..=USERS {
+            let addr = SocketAddr::from(([127, 0, 0, 1], (user_id % 60000) as u16));
+            state.connected(user_id).insert(user_id, addr, user_id);
+        }
+        Self {
+            ids,
+            recent_sends: Sharded::new(shards, || {
+                SendDedup::new(Duration::from_secs(600), 100_000usize.div_ceil(shards))
+            }),
+            rate_limits: Sharded::new(shards, HashMap::new),
+            state,
+        }
+    }
+}
+
+fn run(server: &Server, threads: u64) -> f64 {
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for t in 0..threads {
+            s.spawn(move || {
+                let mut x = t.wrapping_mul(0x9E37_79B9_7F4A_7C15) + 1;
+                for i in 0..OPS_PER_THREAD {
+                    // xorshift, 不为了这个引入 rand
+                    x ^= x << 13;
+                    x ^= x >> 7;
+                    x ^= x << 17;
+                    let from = (x >> 32) % USERS + 1;
+                    let user_id = x % USERS + 1;
+                    let now = Instant::now();
+                    server
+                        .rate_limits
+                        .lock(from)
+                        .entry(from)
+                        .or_insert_with(|| RateLimiter::new(u64::MAX, u64::MAX, now))
+                        .check(2, now);
+                    server
+                        .recent_sends
+                        .lock(from)
+                        .get_or_insert_with(from, -(i as i64), || server.ids.next().unwrap());
+                    let online = server
+                        .state
+                        .connected(user_id)
+                        .clients(user_id)
+                        .is_some_and(|clients| clients.values().all(|&c| c == user_id));
+                    if !online || x & 1 == 0 {
+                        let mut offline = server.state.offline(user_id);
+                        offline.push(user_id, Msg2C::Ok).unwrap();
+                        let (page, _) = offline.page(user_id, 0, 50);
+                        if let Some(&(cursor, _)) = page.last() {
+                            offline.ack(user_id, cursor);
+                        }
+                    }
+                }
+            });
+        }
+    });
+    (threads * OPS_PER_THREAD) as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let many = cpus * 4;
+    let servers: Vec<Server> = [1, many].into_iter().map(Server::new).collect();
+
+    println!("{} cpus, ops/s", cpus);
+    println!(
+        "{:>8} {:>14} {:>14}",
+        "threads",
+        "shards=1",
+        format!("shards={}", many)
+    );
+    let mut threads = 1;
+    while threads <= cpus.max(4) {
+        let res: Vec<f64> = servers.iter().map(|s| run(s, threads as u64)).collect();
+        println!("{:>8} {:>14.0} {:>14.0}", threads, res[0], res[1]);
+        threads *= 2;
+    }
+}
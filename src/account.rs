@@ -0,0 +1,147 @@
+use crate::error::Result;
+use crate::msg::{put_str, read_str, skip, skip_str, FrameMsg};
+use crate::oplog::{Apply, OpLog};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use bytes::Buf;
+use rand::rngs::OsRng;
+use std::collections::HashMap;
+use std::io::{self, Cursor};
+use std::path::PathBuf;
+
+pub const PASSWORD_MAX: usize = 1024; // 防止用超长密码拖慢服务端
+
+// 账号只保存 argon2 的 PHC 字符串（包含随机盐和参数），不保存明文密码
+#[derive(Debug, Clone, PartialEq)]
+pub struct Account {
+    pub user_id: u64,
+    pub hash: String,
+}
+
+impl FrameMsg for Account {
+    fn check(src: &mut Cursor<&[u8]>) -> Result<()> {
+        skip(src, 8)?;
+        skip_str(src)
+    }
+
+    fn parse(src: &mut Cursor<&[u8]>) -> Self {
+        Self {
+            user_id: src.get_u64(),
+            hash: read_str(src),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = vec![];
+        res.extend(self.user_id.to_be_bytes());
+        put_str(&mut res, &self.hash);
+        res
+    }
+}
+
+// 注册过的账号，追加写到文件里，已经注册过的 user_id 不能再注册
+pub struct Accounts {
+    log: OpLog<Account, HashMap<u64, String>>,
+}
+
+impl Apply<Account> for HashMap<u64, String> {
+    fn apply(&mut self, account: &Account) -> bool {
+        if self.contains_key(&account.user_id) {
+            return false;
+        }
+        self.insert(account.user_id, account.hash.clone());
+        true
+    }
+}
+
+impl Accounts {
+    pub fn open(path: Option<PathBuf>) -> io::Result<Self> {
+        Ok(Self {
+            log: OpLog::open(path)?,
+        })
+    }
+
+    pub fn exists(&self, user_id: u64) -> bool {
+        self.log.state().contains_key(&user_id)
+    }
+
+    pub fn hash(&self, user_id: u64) -> Option<String> {
+        self.log.state().get(&user_id).cloned()
+    }
+
+    // 所有注册过的 user_id, 不保证顺序
+    pub fn users(&self) -> impl Iterator<Item = u64> + '_ {
+        self.log.state().keys().copied()
+    }
+
+    // 返回 false 表示 user_id 已经被注册了
+    pub fn insert(&mut self, account: Account) -> io::Result<bool> {
+        self.log.push(account)
+    }
+}
+
+pub fn is_valid_password(password: &str) -> bool {
+    !password.is_empty() && password.len() <= PASSWORD_MAX
+}
+
+// 下面两个函数都故意很慢（几十毫秒），服务端应该放到 spawn_blocking 里调用
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_password() {
+        let hash = hash_password("secret");
+        assert!(!hash.contains("secret"));
+        assert_ne!(hash, hash_password("secret")); // 盐是随机的
+        assert!(verify_password(&hash, "secret"));
+        assert!(!verify_password(&hash, "Secret"));
+        assert!(!verify_password("not a hash", "secret"));
+
+        assert!(is_valid_password("secret"));
+        assert!(!is_valid_password(""));
+        assert!(!is_valid_password(&"a".repeat(PASSWORD_MAX + 1)));
+    }
+
+    #[test]
+    fn test_accounts() {
+        let dir = TempDir::new();
+        let path = dir.file("accounts");
+
+        let mut accounts = Accounts::open(Some(path.clone())).unwrap();
+        let account = Account {
+            user_id: 1,
+            hash: hash_password("secret"),
+        };
+        assert!(accounts.insert(account.clone()).unwrap());
+        assert!(!accounts
+            .insert(Account {
+                user_id: 1,
+                hash: hash_password("other"),
+            })
+            .unwrap());
+        drop(accounts); // 模拟重启
+
+        let accounts = Accounts::open(Some(path.clone())).unwrap();
+        assert_eq!(accounts.hash(1), Some(account.hash));
+        assert!(!accounts.exists(2));
+        assert_eq!(accounts.users().collect::<Vec<u64>>(), vec![1]);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::error::Result;
+use crate::msg::{skip, FrameMsg};
+use crate::oplog::{Apply, OpLog};
+use bytes::Buf;
+use std::collections::BTreeSet;
+use std::io::{self, Cursor};
+use std::path::PathBuf;
+
+// 封禁和解封的操作记录，和屏蔽一样追加写到文件里，启动时按顺序重放
+#[derive(Debug, Clone, PartialEq)]
+pub struct BanOp {
+    pub user_id: u64,
+    pub banned: bool,
+}
+
+impl FrameMsg for BanOp {
+    fn check(src: &mut Cursor<&[u8]>) -> Result<()> {
+        skip(src, 9)
+    }
+
+    fn parse(src: &mut Cursor<&[u8]>) -> Self {
+        Self {
+            user_id: src.get_u64(),
+            banned: src.get_u8() != 0,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = vec![];
+        res.extend(self.user_id.to_be_bytes());
+        res.push(self.banned as u8);
+        res
+    }
+}
+
+// 被管理员封禁的用户，不能登录、恢复会话或者注册
+pub struct BanList {
+    log: OpLog<BanOp, BTreeSet<u64>>,
+}
+
+impl Apply<BanOp> for BTreeSet<u64> {
+    fn apply(&mut self, op: &BanOp) -> bool {
+        if op.banned {
+            self.insert(op.user_id)
+        } else {
+            self.remove(&op.user_id)
+        }
+    }
+}
+
+impl BanList {
+    pub fn open(path: Option<PathBuf>) -> io::Result<Self> {
+        Ok(Self {
+            log: OpLog::open(path)?,
+        })
+    }
+
+    // 返回状态是否有变化，没变化的不写文件
+    pub fn set(&mut self, user_id: u64, banned: bool) -> io::Result<bool> {
+        self.log.push(BanOp { user_id, banned })
+    }
+
+    pub fn is_banned(&self, user_id: u64) -> bool {
+        self.log.state().contains(&user_id)
+    }
+
+    pub fn list(&self) -> Vec<u64> {
+        self.log.state().iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_ban_list() {
+        let dir = TempDir::new();
+        let path = dir.file("bans");
+
+        let mut bans = BanList::open(Some(path.clone())).unwrap();
+        assert!(bans.set(3, true).unwrap());
+        assert!(bans.set(2, true).unwrap());
+        assert!(!bans.set(2, true).unwrap()); // 重复封禁
+        assert!(bans.set(3, false).unwrap());
+        assert!(!bans.set(1, false).unwrap());
+        assert!(bans.is_banned(2));
+        assert!(!bans.is_banned(3));
+        drop(bans); // 模拟重启
+
+        let bans = BanList::open(Some(path.clone())).unwrap();
+        assert_eq!(bans.list(), vec![2]);
+    }
+}
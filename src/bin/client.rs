@@ -1,7 +1,12 @@
 extern crate my_chat;
 use my_chat::connection::Connection;
-use my_chat::msg::{FrameMsg, Msg2C, Msg2S};
+use my_chat::mention::is_mentioned;
+use my_chat::msg::{ErrCode, FrameMsg, Msg2C, Msg2S, Profile, Record, UserInfo};
+use my_chat::time::{
+    format_timestamp, get_current_timestamp, get_current_timestamp_millis, parse_date,
+};
 use regex::Regex;
+use std::collections::{BTreeMap, HashMap};
 use std::io::{stdout, Write};
 use std::sync::{Arc, RwLock};
 use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
@@ -9,9 +14,49 @@ use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 
+const HIGHLIGHT: &str = "\x1b[1;33m"; // 被 @ 的行用黄色高亮
+const RESET: &str = "\x1b[0m";
+const PULL_LIMIT: u64 = 20; // 每次拉取的离线消息条数
+const HISTORY_LIMIT: u64 = 20; // 每次查询的历史消息条数
+const DIRECTORY_LIMIT: u64 = 20; // 每次查询的用户数
+const SERVER_ADDR: &str = "127.0.0.1:8080";
+const RECONNECT_MAX_DELAY: u64 = 30; // 重连间隔从 1 秒开始翻倍，最多这么多秒
+const BEAT_INTERVAL: u64 = 30; // 这么多秒没发过东西就发一个 Beat, 要比服务端的 idle timeout 短
+
+struct Line {
+    expire_at: i64, // 0 表示不过期
+    text: String,
+}
+
 pub struct Console {
     user_id: Option<u64>,
+    // 已经发了 Login, 等服务端回复 Ok
+    pending_login: Option<u64>,
+    // 登录成功后服务端发的 token, 断线重连时用来恢复会话
+    session: Option<String>,
+    // 已经发了 Resume, 等服务端回复
+    resuming: bool,
+    // 服务端说要关闭了，之后收到 Quit 不退出，等它重启后自动重连
+    server_closing: bool,
+    // 还没收到 Update 的消息 fake_msg_id -> 帧，重连后重发，服务端会按 fake_msg_id 去重
+    pending: BTreeMap<i64, Msg2S>,
     send_to: Option<u64>,
+    // 发送消息的阅后即焚时间，0 表示不过期
+    ttl: u64,
+    // 已经 ack 的离线消息位置
+    pull_cursor: u64,
+    // (peer, 已经显示的最早的 msg_id), 用于继续往前翻历史
+    history_cursor: Option<(u64, u64)>,
+    // 上一次搜索，before_msg_id 会更新为已显示的最早的结果，用于翻页
+    last_search: Option<Msg2S>,
+    // 搜索结果 msg_id -> 所在会话的 peer, 用于 !jump
+    search_hits: HashMap<u64, u64>,
+    // 上一次查询的用户列表，after_user_id 会更新为已显示的最后一个用户，用于翻页
+    last_who: Option<Msg2S>,
+    // 已知的用户资料，正在查询的用户先放一个空的，避免重复查询
+    profiles: HashMap<u64, Profile>,
+    // 本地收到的消息，阅后即焚的过期后会被删掉
+    lines: Vec<Line>,
     //input_string: String, //TODO: 能否得到输入了一半但没按回车的字符
 }
 
@@ -19,19 +64,94 @@ impl Console {
     fn new() -> Self {
         Self {
             user_id: None,
+            pending_login: None,
+            session: None,
+            resuming: false,
+            server_closing: false,
+            pending: BTreeMap::new(),
             send_to: None,
+            ttl: 0,
+            pull_cursor: 0,
+            history_cursor: None,
+            last_search: None,
+            search_hits: HashMap::new(),
+            last_who: None,
+            profiles: HashMap::new(),
+            lines: vec![],
             //input_string: String::new(),
         }
     }
 
     fn login(&mut self, user_id: u64) {
+        // 切换了账号的话，跟之前账号相关的状态都要清掉，离线消息要从头拉取
+        // 之前账号没发完的消息也不要了，否则断线重连时会用新账号的 token 发出去
+        // 服务端先回 Ok 再发新的 Session, 所以这里可以放心清掉旧的 token
+        if self.user_id.is_some_and(|id| id != user_id) {
+            self.session = None;
+            self.pending.clear();
+            self.pull_cursor = 0;
+            self.history_cursor = None;
+            self.last_search = None;
+            self.search_hits.clear();
+            self.lines.clear();
+        }
         self.user_id = Some(user_id);
     }
 
+    // 有名字时显示为 name(user_id)
+    fn display(&self, user_id: u64) -> String {
+        match self.profiles.get(&user_id) {
+            Some(p) if !p.name.is_empty() => format!("{}({})", p.name, user_id),
+            _ => user_id.to_string(),
+        }
+    }
+
+    // 还不知道资料的用户，返回是否需要向服务端查询
+    fn need_profile(&mut self, user_id: u64) -> bool {
+        if self.profiles.contains_key(&user_id) {
+            return false;
+        }
+        self.profiles.insert(
+            user_id,
+            Profile {
+                user_id,
+                ..Default::default()
+            },
+        );
+        true
+    }
+
     fn send_to(&mut self, user_id: u64) {
         self.send_to = Some(user_id);
     }
 
+    fn set_ttl(&mut self, ttl: u64) {
+        self.ttl = ttl;
+    }
+
+    fn show(&mut self, expire_at: i64, text: String) {
+        println!("\n{}", text);
+        self.lines.push(Line { expire_at, text });
+    }
+
+    // 返回是否有消息过期
+    fn expire(&mut self, now: i64) -> bool {
+        let n = self.lines.len();
+        self.lines
+            .retain(|line| line.expire_at == 0 || line.expire_at > now);
+        n != self.lines.len()
+    }
+
+    // 终端里已经打印的内容删不掉，只能清屏后重新打印
+    fn redraw(&self) {
+        // 3J 连终端的回滚缓冲一起清掉，否则往上翻还能看到已经销毁的消息
+        print!("\x1b[2J\x1b[3J\x1b[H");
+        for line in &self.lines {
+            println!("{}", line.text);
+        }
+        self.newline();
+    }
+
     fn help(&self) {
         println!("界面介绍（以行为单位）：");
         println!("user_id> 表示等待输入消息发送给对方");
@@ -39,13 +159,45 @@ impl Console {
 
         println!();
         println!("特殊命令：");
-        println!("!login your_user_id    登录");
+        println!("!register your_user_id 注册账号（会提示输入密码）");
+        println!("!login your_user_id    登录（会提示输入密码）");
         println!("!to send_to_user_id    改变聊天对象");
-        println!("!quit                  退出客户端");
-        println!("!pull                  获取离线消息");
+        println!("!mute user_id          静音和对方的会话（被 @ 时仍会提醒）");
+        println!("!unmute user_id        取消静音");
+        println!("!block user_id         屏蔽对方：不再收到他的消息，他也看不到你是否在线");
+        println!("!unblock user_id       取消屏蔽");
+        println!("!blocked               列出屏蔽的用户");
+        println!("!contact add|accept|decline|remove user_id");
+        println!("                       发送、接受、拒绝联系人请求，或者删除联系人");
+        println!("!contacts              列出联系人和收到的请求");
+        println!("!contacts-only on|off  只接收联系人的消息");
+        println!("!ttl seconds           之后发送的消息阅后即焚，0 表示取消");
+        println!("!name [name]           设置（不带参数时清除）自己的名字");
+        println!("!status [text]         设置（不带参数时清除）自己的状态");
+        println!("!profile user_id       查看对方的资料");
+        println!("!quit                  退出登录并关闭客户端");
+        println!(
+            "!pull                  获取离线消息（分页，每次 {} 条）",
+            PULL_LIMIT
+        );
+        println!(
+            "!history [user_id]     查看和对方（默认当前聊天对象）的历史消息，再次输入继续往前翻"
+        );
+        println!("!search [from:id] [peer:id] [since:2024-01-01] [until:2024-02-01] [-w] text");
+        println!("                       搜索自己的历史消息，-w 按词匹配，不带参数时继续翻页");
+        println!("!jump msg_id           查看搜索结果所在会话的上下文");
+        println!("!who [all]             列出在线（或全部）用户，!who next 继续翻页");
+        println!("!multi id1,id2 msg     同一条消息发给多个人");
+        println!("!broadcast msg         发给所有用户（需要管理员权限）");
+        println!("!announce msg          给在线用户发全服公告（需要管理员权限）");
+        println!("!kick user_id          把对方踢下线（需要管理员权限）");
+        println!("!ban user_id           封禁对方，!unban 解封（需要管理员权限）");
+        println!("!stats                 查看服务端队列大小和封禁列表（需要管理员权限）");
+        println!("!react msg_id emoji    给消息加 reaction");
+        println!("!unreact msg_id emoji  撤销 reaction");
         println!("!help                  打印本帮助信息");
         println!();
-        println!("操作流程：先登录，指定要发消息的 user_id，然后开始聊天吧");
+        println!("操作流程：先注册并登录，指定要发消息的 user_id，然后开始聊天吧");
         println!();
         self.newline();
     }
@@ -59,7 +211,11 @@ impl Console {
         }
 
         if let Some(user_id) = self.send_to {
-            print!("to {}> ", user_id);
+            if self.ttl > 0 {
+                print!("to {} (ttl {}s)> ", self.display(user_id), self.ttl);
+            } else {
+                print!("to {}> ", self.display(user_id));
+            }
         } else {
             print!("to server only> ");
         }
@@ -77,12 +233,11 @@ async fn main() {
 
     let console = Arc::new(RwLock::new(console));
     let console_cloned = console.clone();
+    let console_expire = console.clone();
     // 几乎是不怎么变化的，所以用 RwLock 很合适
 
-    let socket = TcpStream::connect("127.0.0.1:8080").await.unwrap();
-    let (reader, writer) = socket.into_split();
-
     let (tx, rx) = mpsc::channel(2);
+    let tx_ack = tx.clone();
 
     // tokio::select!(recv_loop
     // tokio::join!(
@@ -93,56 +248,451 @@ async fn main() {
     // join 会等待全部任务完成，而 select 则是最短任务完成
     tokio::select!(
     _ = main_loop(tx, console) => {},
-    _ = send_loop(rx, writer) => {},
-    _ = recv_loop(reader, console_cloned) => {},
+    _ = connection_loop(rx, console_cloned, tx_ack) => {},
+    _ = expire_loop(console_expire) => {},
     );
 }
 
-async fn recv_loop(reader: OwnedReadHalf, console: Arc<RwLock<Console>>) {
+// 断线后自动重连，有 token 的话恢复会话并重发还没确认的消息
+async fn connection_loop(
+    mut rx: mpsc::Receiver<Msg2S>,
+    console: Arc<RwLock<Console>>,
+    tx: mpsc::Sender<Msg2S>,
+) {
+    let mut delay = 1;
+    loop {
+        let socket = match TcpStream::connect(SERVER_ADDR).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                println!("\n连接服务器失败: {}, {} 秒后重试", e, delay);
+                tokio::time::sleep(std::time::Duration::from_secs(delay)).await;
+                delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                continue;
+            }
+        };
+        delay = 1;
+        let (reader, writer) = socket.into_split();
+        let mut writer = BufWriter::new(writer);
+        if resume(&mut writer, &console).await.is_err() {
+            continue;
+        }
+
+        tokio::select!(
+        res = send_loop(&mut rx, &mut writer, &console) => {
+            if res.is_ok() {
+                return; // main_loop 已经结束了
+            }
+        },
+        quit = recv_loop(reader, console.clone(), tx.clone()) => {
+            if quit {
+                return;
+            }
+        },
+        );
+        println!("\n和服务器的连接断开了，正在重连...");
+    }
+}
+
+async fn resume(
+    writer: &mut BufWriter<OwnedWriteHalf>,
+    console: &RwLock<Console>,
+) -> std::io::Result<()> {
+    let frames: Vec<Msg2S> = {
+        let mut console = console.write().unwrap();
+        match console.session.clone() {
+            Some(token) => {
+                console.resuming = true;
+                // 服务端是按顺序处理的，Resume 成功之后这些消息才会被处理
+                std::iter::once(Msg2S::Resume { token })
+                    .chain(console.pending.values().rev().cloned())
+                    .collect()
+            }
+            None => vec![],
+        }
+    };
+    for frame in frames {
+        writer.write_all(&frame.to_bytes()).await?;
+    }
+    writer.flush().await
+}
+
+// 返回是否是服务端让退出的，否则就是连接断了
+async fn recv_loop(
+    reader: OwnedReadHalf,
+    console: Arc<RwLock<Console>>,
+    tx: mpsc::Sender<Msg2S>,
+) -> bool {
     // NOTE: 如何优雅地打印，是难点，但不是重点，先不做
     // \r 移到行首后，继续输入会是覆盖状态，而不是插入
     // \x08 退格
     let mut conn = Connection::<Msg2C>::new(BufReader::new(reader));
-    while let Some(frame) = conn.read_frame().await.unwrap() {
-        dbg!(&frame);
-        // NOTE: 暂时不保存消息 collections
+    while let Ok(Some(frame)) = conn.read_frame().await {
+        if !frame.has_secret() {
+            dbg!(&frame);
+        }
         match frame {
-            Msg2C::Msg {
-                //msg_id,
-                from,
-                //ts,
-                //len,
-                msg,
-                ..
-            } => {
-                println!("\nfrom {} < {}", from, msg);
+            Msg2C::Quit => {
+                if std::mem::take(&mut console.write().unwrap().server_closing) {
+                    return false;
+                }
+                println!("\nBye");
+                return true;
             }
-            Msg2C::Update {
-                fake_msg_id,
-                real_msg_id,
+            Msg2C::Page {
+                last_msg_id,
+                has_more,
+                msgs,
             } => {
+                for msg in msgs {
+                    show_frame(msg, &console);
+                }
+                // 这一页处理完了再 ack, 服务端才会删除
+                console.write().unwrap().pull_cursor = last_msg_id;
+                tx.send(Msg2S::Ack {
+                    msg_id: last_msg_id,
+                })
+                .await
+                .unwrap();
+                if has_more {
+                    println!("\nfrom server < 还有更多离线消息，输入 !pull 继续");
+                }
+            }
+            frame => {
+                let (pending_login, resuming, pull_cursor) = {
+                    let console = console.read().unwrap();
+                    (console.pending_login, console.resuming, console.pull_cursor)
+                };
+                if let Msg2C::Ok = frame {
+                    if let Some(user_id) = pending_login {
+                        // 登录成功后拿到自己的资料，之后 !name 和 !status 才不会覆盖另一项
+                        console.write().unwrap().need_profile(user_id);
+                        tx.send(Msg2S::GetProfile { user_id }).await.unwrap();
+                    } else if resuming {
+                        // 断线期间没收到的消息在服务端的离线消息里
+                        tx.send(Msg2S::Pull {
+                            since_msg_id: pull_cursor,
+                            limit: PULL_LIMIT,
+                        })
+                        .await
+                        .unwrap();
+                    }
+                }
+                // 第一次收到某人的消息时查询他的资料，之后就能显示名字了
+                if let Msg2C::Msg { from, .. } | Msg2C::Mention { from, .. } = &frame {
+                    if console.write().unwrap().need_profile(*from) {
+                        tx.send(Msg2S::GetProfile { user_id: *from }).await.unwrap();
+                    }
+                }
+                show_frame(frame, &console);
+            }
+        }
+
+        console.read().unwrap().newline();
+    }
+    false
+}
+
+fn show_frame(frame: Msg2C, console: &RwLock<Console>) {
+    // NOTE: 只保存收到的消息，用于阅后即焚过期后重绘
+    match frame {
+        Msg2C::Msg {
+            msg_id,
+            from,
+            //ts,
+            expire_at,
+            //len,
+            msg,
+            ..
+        } => {
+            let mut console = console.write().unwrap();
+            let mut text = format!("from {} < {} (#{})", console.display(from), msg, msg_id);
+            if expire_at > 0 {
+                text = format!("{} [阅后即焚]", text);
+            }
+            if console
+                .user_id
+                .is_some_and(|user_id| is_mentioned(&msg, user_id))
+            {
+                text = format!("{}{}{}", HIGHLIGHT, text, RESET);
+            }
+            console.show(expire_at, text);
+        }
+        Msg2C::Mention {
+            msg_id,
+            from,
+            expire_at,
+            msg,
+        } => {
+            let mut console = console.write().unwrap();
+            let text = format!(
+                "{}from {} @you < {} (#{}){}",
+                HIGHLIGHT,
+                console.display(from),
+                msg,
+                msg_id,
+                RESET
+            );
+            console.show(expire_at, text);
+        }
+        Msg2C::Update {
+            fake_msg_id,
+            real_msg_id,
+        } => {
+            console.write().unwrap().pending.remove(&fake_msg_id);
+            println!(
+                "\nfrom server < Update msg_id from {} to {}",
+                fake_msg_id, real_msg_id
+            );
+        }
+        Msg2C::Reactions { msg_id, reactions } => {
+            let lst: Vec<String> = reactions
+                .iter()
+                .map(|(emoji, count)| format!("{} {}", emoji, count))
+                .collect();
+            println!("\nfrom server < #{} reactions: {}", msg_id, lst.join(", "));
+        }
+        Msg2C::History {
+            peer,
+            has_more,
+            records,
+        } => {
+            println!();
+            records.iter().for_each(print_record);
+            if let Some(first) = records.first() {
+                console.write().unwrap().history_cursor = Some((peer, first.msg_id));
+            }
+            if has_more {
+                println!("from server < 还有更早的消息，输入 !history 继续");
+            } else {
+                println!("from server < 没有更早的消息了");
+            }
+        }
+        Msg2C::SearchResult { has_more, records } => {
+            println!();
+            records.iter().for_each(print_record);
+            let mut console = console.write().unwrap();
+            let user_id = console.user_id.unwrap_or_default();
+            for r in &records {
+                let peer = if r.from == user_id { r.to } else { r.from };
+                console.search_hits.insert(r.msg_id, peer);
+            }
+            if let (Some(Msg2S::Search { before_msg_id, .. }), Some(last)) =
+                (console.last_search.as_mut(), records.last())
+            {
+                *before_msg_id = last.msg_id;
+            }
+            if records.is_empty() {
+                println!("from server < 没有找到");
+            } else if has_more {
+                println!("from server < 还有更多结果，输入 !search 继续，!jump msg_id 查看上下文");
+            } else {
+                println!("from server < 没有更多结果了，!jump msg_id 查看上下文");
+            }
+        }
+        Msg2C::Directory { has_more, users } => {
+            println!();
+            users.iter().for_each(print_user);
+            if let (Some(Msg2S::Directory { after_user_id, .. }), Some(last)) =
+                (console.write().unwrap().last_who.as_mut(), users.last())
+            {
+                *after_user_id = last.user_id;
+            }
+            if users.is_empty() {
+                println!("from server < 没有用户");
+            } else if has_more {
+                println!("from server < 还有更多用户，输入 !who next 继续");
+            }
+        }
+        Msg2C::Profile { profile } => {
+            if profile.name.is_empty() && profile.status.is_empty() {
+                println!("\nfrom server < {} 没有设置资料", profile.user_id);
+            } else {
                 println!(
-                    "\nfrom server < Update msg_id from {} to {}",
-                    fake_msg_id, real_msg_id
+                    "\nfrom server < {} name: {}, status: {}",
+                    profile.user_id, profile.name, profile.status
                 );
             }
-            Msg2C::Quit => {
-                println!("\nBye");
-                return;
+            console
+                .write()
+                .unwrap()
+                .profiles
+                .insert(profile.user_id, profile);
+        }
+        Msg2C::Page { .. } | Msg2C::Quit => {} // 在 recv_loop 中处理
+        Msg2C::Session { token, .. } => {
+            console.write().unwrap().session = Some(token);
+        }
+        Msg2C::Blocked { users } => {
+            let console = console.read().unwrap();
+            let lst: Vec<String> = users.iter().map(|&id| console.display(id)).collect();
+            println!("\nfrom server < 已屏蔽: {}", lst.join(", "));
+        }
+        Msg2C::Contacts {
+            contacts,
+            requests,
+            contacts_only,
+        } => {
+            let console = console.read().unwrap();
+            let contacts: Vec<String> = contacts.iter().map(|&id| console.display(id)).collect();
+            let requests: Vec<String> = requests.iter().map(|&id| console.display(id)).collect();
+            println!("\nfrom server < 联系人: {}", contacts.join(", "));
+            println!("from server < 待处理的请求: {}", requests.join(", "));
+            if contacts_only {
+                println!("from server < 只接收联系人的消息");
             }
-            Msg2C::Ok => {
+        }
+        Msg2C::ContactRequest { from } => {
+            println!(
+                "\nfrom server < {} 请求添加你为联系人，输入 !contact accept {} 接受",
+                console.read().unwrap().display(from),
+                from
+            );
+        }
+        Msg2C::ContactAccepted { peer } => {
+            println!(
+                "\nfrom server < 你和 {} 已经是联系人了",
+                console.read().unwrap().display(peer)
+            );
+        }
+        Msg2C::Announce { from, msg } => {
+            println!(
+                "\n[公告] {}: {}",
+                console.read().unwrap().display(from),
+                msg
+            );
+        }
+        Msg2C::Stats {
+            connected,
+            users,
+            msg_queue,
+            offline_users,
+            offline_msgs,
+            banned,
+        } => {
+            println!(
+                "\nfrom server < 在线 {} / 用户 {}, 待发送 {}, 离线消息 {} 条 ({} 人)",
+                connected, users, msg_queue, offline_msgs, offline_users
+            );
+            println!("from server < 封禁: {:?}", banned);
+        }
+        Msg2C::Presence { user_id, online } => {
+            let status = if online { "上线了" } else { "下线了" };
+            let console = console.read().unwrap();
+            println!("\nfrom server < {} {}", console.display(user_id), status);
+        }
+        Msg2C::Ok => {
+            let mut console = console.write().unwrap();
+            if let Some(user_id) = console.pending_login.take() {
+                console.login(user_id);
+                println!("\nfrom server < 登录成功");
+            } else if console.resuming {
+                console.resuming = false;
+                println!("\nfrom server < 已恢复会话");
+            } else {
                 println!("\nfrom server < Ok");
             }
-            Msg2C::Err => {
-                println!("\nfrom server < Err");
-            }
-            Msg2C::AuthRequired => {
-                println!("\nfrom server < Authorization Required");
+        }
+        Msg2C::Err { code } => {
+            let mut console = console.write().unwrap();
+            if code == ErrCode::AuthFailed && console.resuming {
+                console.resuming = false;
+                console.session = None;
+                console.user_id = None;
+                println!("\nfrom server < 会话已失效，请重新登录");
+            } else if code == ErrCode::Replaced {
+                println!("\nfrom server < 账号在其他地方登录了");
+            } else if code == ErrCode::ShuttingDown {
+                console.server_closing = true;
+                println!("\nfrom server < 服务器正在关闭，稍后会自动重连");
+            } else {
+                // 登录或恢复会话失败时可能回复的错误，不清掉的话之后无关的 Ok 会被当成登录成功
+                if matches!(
+                    code,
+                    ErrCode::AuthFailed
+                        | ErrCode::AlreadyOnline
+                        | ErrCode::Banned
+                        | ErrCode::Busy
+                        | ErrCode::RateLimited
+                        | ErrCode::Unknown
+                ) {
+                    console.pending_login = None;
+                    console.resuming = false;
+                }
+                if code == ErrCode::AlreadyOnline {
+                    println!("\nfrom server < 账号已经在其他地方登录了");
+                } else {
+                    println!("\nfrom server < Err: {:?}", code);
+                }
             }
         }
+        Msg2C::AuthRequired => {
+            println!("\nfrom server < Authorization Required");
+        }
+    }
+}
 
-        console.read().unwrap().newline();
+fn print_record(r: &Record) {
+    println!(
+        "[{}] {} > {}: {} (#{})",
+        format_timestamp(r.ts),
+        r.from,
+        r.to,
+        r.msg,
+        r.msg_id
+    );
+}
+
+fn print_user(u: &UserInfo) {
+    let status = if u.online { "online" } else { "offline" };
+    if u.name.is_empty() {
+        println!("{} [{}]", u.user_id, status);
+    } else {
+        println!("{} ({}) [{}]", u.user_id, u.name, status);
+    }
+}
+
+// !search 后面的参数，选项之外的部分都是要搜索的内容
+fn parse_search(args: &str) -> Option<Msg2S> {
+    let (mut sender, mut peer, mut since_ts, mut until_ts) = (0, 0, 0, 0);
+    let mut whole_word = false;
+    let mut words = vec![];
+    for arg in args.split_whitespace() {
+        match arg.split_once(':') {
+            Some(("from", v)) => sender = v.parse().ok()?,
+            Some(("peer", v)) => peer = v.parse().ok()?,
+            Some(("since", v)) => since_ts = parse_date(v)?,
+            Some(("until", v)) => until_ts = parse_date(v)?,
+            _ if arg == "-w" => whole_word = true,
+            _ => words.push(arg),
+        }
     }
+    if words.is_empty() {
+        return None;
+    }
+    Some(Msg2S::Search {
+        query: words.join(" "),
+        whole_word,
+        sender,
+        peer,
+        since_ts,
+        until_ts,
+        before_msg_id: 0,
+        limit: HISTORY_LIMIT,
+    })
+}
+
+// 不回显地读密码，注册时要输入两次
+async fn read_password(confirm: bool) -> Option<String> {
+    tokio::task::spawn_blocking(move || {
+        let password = rpassword::prompt_password("password: ").ok()?;
+        if confirm && rpassword::prompt_password("confirm password: ").ok()? != password {
+            println!("两次输入的密码不一致");
+            return None;
+        }
+        Some(password)
+    })
+    .await
+    .unwrap()
 }
 
 async fn main_loop(tx: mpsc::Sender<Msg2S>, console: Arc<RwLock<Console>>) {
@@ -150,9 +700,25 @@ async fn main_loop(tx: mpsc::Sender<Msg2S>, console: Arc<RwLock<Console>>) {
     // io::stdin() 挺好用的，不需要把控制台读取单独做一个任务
     let mut input_reader = BufReader::new(io::stdin());
     let mut input_string = String::new();
-    let mut fake_msg_id = 0;
+    // 服务端会按 (user_id, fake_msg_id) 去重，客户端重启后不能复用之前的 fake_msg_id
+    let mut fake_msg_id = -get_current_timestamp_millis() * 1000;
 
-    let reg_set = Regex::new(r"^!(login|to)\s+(\d+)").unwrap();
+    let reg_auth = Regex::new(r"^!(login|register)\s+(\d+)").unwrap();
+    let reg_set = Regex::new(r"^!(to|mute|unmute|block|unblock)\s+(\d+)").unwrap();
+    let reg_contact = Regex::new(r"^!contact\s+(add|accept|decline|remove)\s+(\d+)").unwrap();
+    let reg_contacts_only = Regex::new(r"^!contacts-only\s+(on|off)$").unwrap();
+    let reg_history = Regex::new(r"^!history(?:\s+(\d+))?$").unwrap();
+    let reg_search = Regex::new(r"^!search(?:\s+(.+))?$").unwrap();
+    let reg_jump = Regex::new(r"^!jump\s+(\d+)").unwrap();
+    let reg_who = Regex::new(r"^!who(?:\s+(all|next))?$").unwrap();
+    let reg_profile = Regex::new(r"^!(name|status)(?:\s+(.+))?$").unwrap();
+    let reg_get_profile = Regex::new(r"^!profile\s+(\d+)").unwrap();
+    let reg_ttl = Regex::new(r"^!ttl\s+(\d+)").unwrap();
+    let reg_react = Regex::new(r"^!(react|unreact)\s+(\d+)\s+(\S+)").unwrap();
+    let reg_multi = Regex::new(r"^!multi\s+([\d,]+)\s+(.+)").unwrap();
+    let reg_broadcast = Regex::new(r"^!broadcast\s+(.+)").unwrap();
+    let reg_announce = Regex::new(r"^!announce\s+(.+)").unwrap();
+    let reg_admin = Regex::new(r"^!(kick|ban|unban)\s+(\d+)").unwrap();
 
     loop {
         input_string.clear();
@@ -162,38 +728,251 @@ async fn main_loop(tx: mpsc::Sender<Msg2S>, console: Arc<RwLock<Console>>) {
 
         match input_string.as_str() {
             "" => continue,
-            "!quit" => return,
+            "!quit" => {
+                // 服务端回复 Quit 后 connection_loop 会结束，断线的话就不等了
+                let _ =
+                    tokio::time::timeout(std::time::Duration::from_secs(3), tx.send(Msg2S::Logout))
+                        .await;
+                tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+                return;
+            }
             "!help" => console.read().unwrap().help(),
+            "!contacts" => {
+                console.read().unwrap().newline();
+                tx.send(Msg2S::ListContacts).await.unwrap();
+            }
+            "!blocked" => {
+                console.read().unwrap().newline();
+                tx.send(Msg2S::ListBlocked).await.unwrap();
+            }
+            "!stats" => {
+                console.read().unwrap().newline();
+                tx.send(Msg2S::Stats).await.unwrap();
+            }
             "!pull" => {
                 console.read().unwrap().newline();
-                tx.send(Msg2S::Pull).await.unwrap();
+                let since_msg_id = console.read().unwrap().pull_cursor;
+                tx.send(Msg2S::Pull {
+                    since_msg_id,
+                    limit: PULL_LIMIT,
+                })
+                .await
+                .unwrap();
             }
             _ => {
-                if let Some(caps) = reg_set.captures(&input_string) {
+                if let Some(caps) = reg_auth.captures(&input_string) {
                     let user_id = caps.get(2).unwrap().as_str().parse::<u64>().unwrap();
-                    match caps.get(1).unwrap().as_str() {
-                        "login" => {
-                            console.read().unwrap().newline();
-                            console.write().unwrap().login(user_id);
-                            // user_id 只是一个标记，并非表示登录成功
-                            // 如果验证失败的话，服务端会返回 Msg2C::Quit
-                            tx.send(Msg2S::Login { user_id }).await.unwrap();
+                    let register = caps.get(1).unwrap().as_str() == "register";
+                    if let Some(password) = read_password(register).await {
+                        if register {
+                            tx.send(Msg2S::Register { user_id, password })
+                                .await
+                                .unwrap();
+                        } else {
+                            // 服务端回复 Ok 之后才算登录成功，失败的话会回复 Err
+                            console.write().unwrap().pending_login = Some(user_id);
+                            tx.send(Msg2S::Login { user_id, password }).await.unwrap();
                         }
+                    }
+                    console.read().unwrap().newline();
+                } else if let Some(caps) = reg_set.captures(&input_string) {
+                    let user_id = caps.get(2).unwrap().as_str().parse::<u64>().unwrap();
+                    match caps.get(1).unwrap().as_str() {
                         "to" => {
                             console.write().unwrap().send_to(user_id);
                             console.read().unwrap().newline();
                         }
+                        "mute" => {
+                            console.read().unwrap().newline();
+                            tx.send(Msg2S::Mute { peer: user_id }).await.unwrap();
+                        }
+                        "unmute" => {
+                            console.read().unwrap().newline();
+                            tx.send(Msg2S::Unmute { peer: user_id }).await.unwrap();
+                        }
+                        "block" => {
+                            console.read().unwrap().newline();
+                            tx.send(Msg2S::Block { peer: user_id }).await.unwrap();
+                        }
+                        "unblock" => {
+                            console.read().unwrap().newline();
+                            tx.send(Msg2S::Unblock { peer: user_id }).await.unwrap();
+                        }
                         _ => unimplemented!(),
                     }
+                } else if let Some(caps) = reg_contact.captures(&input_string) {
+                    console.read().unwrap().newline();
+                    let peer = caps.get(2).unwrap().as_str().parse::<u64>().unwrap();
+                    let msg = match caps.get(1).unwrap().as_str() {
+                        "add" => Msg2S::AddContact { peer },
+                        "accept" => Msg2S::AcceptContact { peer },
+                        "decline" => Msg2S::DeclineContact { peer },
+                        _ => Msg2S::RemoveContact { peer },
+                    };
+                    tx.send(msg).await.unwrap();
+                } else if let Some(caps) = reg_contacts_only.captures(&input_string) {
+                    console.read().unwrap().newline();
+                    let on = caps.get(1).unwrap().as_str() == "on";
+                    tx.send(Msg2S::ContactsOnly { on }).await.unwrap();
+                } else if let Some(caps) = reg_history.captures(&input_string) {
+                    console.read().unwrap().newline();
+                    let (send_to, cursor) = {
+                        let console = console.read().unwrap();
+                        (console.send_to, console.history_cursor)
+                    };
+                    // 指定了 peer 时从最新的开始，否则接着上次往前翻
+                    let (peer, before_msg_id) = match caps.get(1) {
+                        Some(m) => (m.as_str().parse::<u64>().unwrap(), 0),
+                        None => match (send_to, cursor) {
+                            (Some(peer), Some((p, before))) if p == peer => (peer, before),
+                            (Some(peer), _) => (peer, 0),
+                            (None, _) => continue,
+                        },
+                    };
+                    tx.send(Msg2S::History {
+                        peer,
+                        before_msg_id,
+                        limit: HISTORY_LIMIT,
+                    })
+                    .await
+                    .unwrap();
+                } else if let Some(caps) = reg_search.captures(&input_string) {
+                    console.read().unwrap().newline();
+                    let search = match caps.get(1) {
+                        Some(args) => parse_search(args.as_str()),
+                        None => console.read().unwrap().last_search.clone(),
+                    };
+                    if let Some(search) = search {
+                        console.write().unwrap().last_search = Some(search.clone());
+                        tx.send(search).await.unwrap();
+                    } else {
+                        println!("用法: !search [from:id] [peer:id] [since:2024-01-01] [-w] text");
+                        console.read().unwrap().newline();
+                    }
+                } else if let Some(caps) = reg_jump.captures(&input_string) {
+                    console.read().unwrap().newline();
+                    let msg_id = caps.get(1).unwrap().as_str().parse::<u64>().unwrap();
+                    let peer = console.read().unwrap().search_hits.get(&msg_id).copied();
+                    if let Some(peer) = peer {
+                        // 包含 msg_id 在内的之前的消息，之后 !history 可以继续往前翻
+                        tx.send(Msg2S::History {
+                            peer,
+                            before_msg_id: msg_id + 1,
+                            limit: HISTORY_LIMIT,
+                        })
+                        .await
+                        .unwrap();
+                    }
+                } else if let Some(caps) = reg_who.captures(&input_string) {
+                    console.read().unwrap().newline();
+                    let who = match caps.get(1).map(|m| m.as_str()) {
+                        Some("next") => console.read().unwrap().last_who.clone(),
+                        arg => Some(Msg2S::Directory {
+                            after_user_id: 0,
+                            online_only: arg.is_none(),
+                            limit: DIRECTORY_LIMIT,
+                        }),
+                    };
+                    if let Some(who) = who {
+                        console.write().unwrap().last_who = Some(who.clone());
+                        tx.send(who).await.unwrap();
+                    }
+                } else if let Some(caps) = reg_profile.captures(&input_string) {
+                    console.read().unwrap().newline();
+                    let value = caps.get(2).map_or("", |m| m.as_str()).to_string();
+                    // 服务端是整体替换，另一项用本地记住的自己的资料
+                    let mine = {
+                        let console = console.read().unwrap();
+                        console
+                            .user_id
+                            .and_then(|user_id| console.profiles.get(&user_id).cloned())
+                            .unwrap_or_default()
+                    };
+                    let msg = match caps.get(1).unwrap().as_str() {
+                        "name" => Msg2S::SetProfile {
+                            name: value,
+                            status: mine.status,
+                        },
+                        _ => Msg2S::SetProfile {
+                            name: mine.name,
+                            status: value,
+                        },
+                    };
+                    tx.send(msg).await.unwrap();
+                } else if let Some(caps) = reg_get_profile.captures(&input_string) {
+                    console.read().unwrap().newline();
+                    let user_id = caps.get(1).unwrap().as_str().parse::<u64>().unwrap();
+                    tx.send(Msg2S::GetProfile { user_id }).await.unwrap();
+                } else if let Some(caps) = reg_ttl.captures(&input_string) {
+                    let ttl = caps.get(1).unwrap().as_str().parse::<u64>().unwrap();
+                    console.write().unwrap().set_ttl(ttl);
+                    console.read().unwrap().newline();
+                } else if let Some(caps) = reg_react.captures(&input_string) {
+                    console.read().unwrap().newline();
+                    let msg_id = caps.get(2).unwrap().as_str().parse::<u64>().unwrap();
+                    let emoji = caps.get(3).unwrap().as_str().to_string();
+                    let msg = match caps.get(1).unwrap().as_str() {
+                        "react" => Msg2S::React { msg_id, emoji },
+                        _ => Msg2S::Unreact { msg_id, emoji },
+                    };
+                    tx.send(msg).await.unwrap();
+                } else if let Some(caps) = reg_multi.captures(&input_string) {
+                    console.read().unwrap().newline();
+                    let to = caps
+                        .get(1)
+                        .unwrap()
+                        .as_str()
+                        .split(',')
+                        .filter_map(|x| x.parse::<u64>().ok())
+                        .collect();
+                    let ttl = console.read().unwrap().ttl;
+                    fake_msg_id -= 1;
+                    tx.send(Msg2S::MultiMsg {
+                        fake_msg_id,
+                        to,
+                        ttl,
+                        msg: caps.get(2).unwrap().as_str().to_string(),
+                    })
+                    .await
+                    .unwrap();
+                } else if let Some(caps) = reg_broadcast.captures(&input_string) {
+                    console.read().unwrap().newline();
+                    let ttl = console.read().unwrap().ttl;
+                    fake_msg_id -= 1;
+                    tx.send(Msg2S::Broadcast {
+                        fake_msg_id,
+                        ttl,
+                        msg: caps.get(1).unwrap().as_str().to_string(),
+                    })
+                    .await
+                    .unwrap();
+                } else if let Some(caps) = reg_announce.captures(&input_string) {
+                    console.read().unwrap().newline();
+                    let msg = caps.get(1).unwrap().as_str().to_string();
+                    tx.send(Msg2S::Announce { msg }).await.unwrap();
+                } else if let Some(caps) = reg_admin.captures(&input_string) {
+                    console.read().unwrap().newline();
+                    let user_id = caps.get(2).unwrap().as_str().parse::<u64>().unwrap();
+                    let msg = match caps.get(1).unwrap().as_str() {
+                        "kick" => Msg2S::Kick { user_id },
+                        "ban" => Msg2S::Ban { user_id },
+                        _ => Msg2S::Unban { user_id },
+                    };
+                    tx.send(msg).await.unwrap();
                 } else {
                     console.read().unwrap().newline();
-                    if let Some(user_id) = console.read().unwrap().send_to {
+                    let (send_to, ttl) = {
+                        let console = console.read().unwrap();
+                        (console.send_to, console.ttl)
+                    };
+                    if let Some(user_id) = send_to {
                         fake_msg_id -= 1;
 
                         tx.send(Msg2S::Msg {
                             fake_msg_id,
                             to: user_id,
-                            len: input_string.bytes().len() as u64,
+                            ttl,
+                            len: input_string.len() as u64,
                             msg: input_string.clone(),
                         })
                         .await
@@ -205,14 +984,45 @@ async fn main_loop(tx: mpsc::Sender<Msg2S>, console: Arc<RwLock<Console>>) {
     }
 }
 
-async fn send_loop(mut rx: mpsc::Receiver<Msg2S>, writer: OwnedWriteHalf) {
+async fn expire_loop(console: Arc<RwLock<Console>>) {
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        if console.write().unwrap().expire(get_current_timestamp()) {
+            console.read().unwrap().redraw();
+        }
+    }
+}
+
+// rx 关闭（main_loop 结束）时返回 Ok, 写失败（连接断了）时返回 Err
+async fn send_loop(
+    rx: &mut mpsc::Receiver<Msg2S>,
+    writer: &mut BufWriter<OwnedWriteHalf>,
+    console: &RwLock<Console>,
+) -> std::io::Result<()> {
     // 发送任务很耗时的话，需要不影响不依赖发送的任务 (比如 !to)
     // 所以这里把发送单独分出来了
-    let mut writer = BufWriter::new(writer);
-    while let Some(msg) = rx.recv().await {
+    let interval = std::time::Duration::from_secs(BEAT_INTERVAL);
+    loop {
+        let msg = match tokio::time::timeout(interval, rx.recv()).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => break,
+            Err(_) => Msg2S::Beat,
+        };
         // tokio::time::sleep(std::time::Duration::from_secs(10)).await;
         // 用于测试耗时任务
-        writer.write_all(&msg.to_bytes()).await.unwrap();
-        writer.flush().await.unwrap();
+        if let Msg2S::Msg { fake_msg_id, .. }
+        | Msg2S::MultiMsg { fake_msg_id, .. }
+        | Msg2S::Broadcast { fake_msg_id, .. } = &msg
+        {
+            // 先记下来，万一这次没发出去，重连后再发
+            console
+                .write()
+                .unwrap()
+                .pending
+                .insert(*fake_msg_id, msg.clone());
+        }
+        writer.write_all(&msg.to_bytes()).await?;
+        writer.flush().await?;
     }
+    Ok(())
 }
@@ -1,57 +1,287 @@
 extern crate my_chat;
+use my_chat::account::{self, Account, Accounts};
+use my_chat::ban::BanList;
+use my_chat::block::BlockList;
+use my_chat::config::{LoginPolicy, ServerConfig};
 use my_chat::connection::Connection;
-use my_chat::msg::{FrameMsg, Msg2C, Msg2S};
+use my_chat::contact::{ContactOp, Contacts};
+use my_chat::dedup::SendDedup;
+use my_chat::history::{History, Query};
+use my_chat::id::IdAllocator;
+use my_chat::limit::{ConnGuard, ConnLimiter};
+use my_chat::mention::parse_mentions;
+use my_chat::msg::{ErrCode, FrameMsg, Msg2C, Msg2S, Profile, Record, UserInfo};
+use my_chat::profile::{self, Profiles};
+use my_chat::ratelimit::{RateLimiter, TokenBucket};
+use my_chat::reaction::{Reactions, RecentReactions};
+use my_chat::session::Sessions;
+use my_chat::shard::Sharded;
+use my_chat::state::ServerState;
 use my_chat::time::get_current_timestamp;
 
-use std::collections::{HashMap, VecDeque};
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
 
 use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
 use tokio::net::tcp::OwnedWriteHalf;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, Notify, OwnedSemaphorePermit, Semaphore};
 
-type MsgQueue = Arc<Mutex<VecDeque<(u64, Vec<u8>)>>>;
-type PushDict = Arc<Mutex<HashMap<u64, Vec<Vec<u8>>>>>;
-type Connected = Arc<Mutex<HashMap<u64, Option<BufWriter<OwnedWriteHalf>>>>>;
+type Writer = BufWriter<OwnedWriteHalf>;
+type Outbox = mpsc::Sender<(u64, Msg2C)>; // (接收者, 消息), 接收者为 0 表示只发给这个连接
+type State = Arc<ServerState<Client>>; // 在线连接和离线消息，按 user_id 分片
+                                       // 投递时要查的表按 user_id 或 msg_id 分片，不同用户之间不会都等同一把锁
+type ReactionDict = Arc<Sharded<RecentReactions>>; // 按 msg_id 分片
+type Muted = Arc<Sharded<HashMap<u64, HashSet<u64>>>>; // user_id -> 被静音的 peers
+type Users = Arc<Mutex<BTreeSet<u64>>>; // 注册过或者出现在历史消息里的所有用户，有序是为了用户列表分页
+type RateLimits = Arc<Sharded<HashMap<u64, RateLimiter>>>; // 每个用户的限流，所有连接共用
 
-#[tokio::main]
-async fn main() {
-    let listener = TcpListener::bind("127.0.0.1:8080").await.unwrap();
+// 记住最近 10 分钟内最多 10 万条 (sender, fake_msg_id), 用于去重，按 sender 分片，各分片平分容量
+const DEDUP_TTL: std::time::Duration = std::time::Duration::from_secs(600);
+const DEDUP_CAPACITY: usize = 100_000;
+const PULL_LIMIT: u64 = 50; // 每次 pull 最多返回多少条离线消息
+const HISTORY_LIMIT: u64 = 50; // 每次查询历史或搜索最多返回多少条
+const DIRECTORY_LIMIT: u64 = 100; // 用户列表每页最多多少个
+const MULTI_MSG_LIMIT: usize = 100; // 非管理员多发一次最多发给多少人
+const REACTION_CAPACITY: usize = 100_000; // 只记住最近这么多条消息的 reaction, 各分片平分
 
-    let msg_queue: MsgQueue = Default::default();
-    let push_dict: PushDict = Default::default();
-    let connected: Connected = Default::default();
+// 被限流后还一直发的话断开这个连接：最多容忍连续 10 次，之后每 6 秒恢复一次
+const RATE_LIMIT_STRIKES: f64 = 10.0;
+const RATE_LIMIT_FORGIVE: f64 = 1.0 / 6.0;
+// 每个连接最多积压 1024 条没写出去的消息，满了说明客户端太慢，断开让他之后 pull 离线消息
+const OUTBOX_CAPACITY: usize = 1024;
+const WRITE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5); // 关闭时最多等这么久把消息写完
+const ABORT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1); // 写不完的再等这么久挪到离线消息里
+const SHARDS_PER_CPU: usize = 4; // ServerState 的分片数，多一些可以减少不同用户撞到同一把锁
 
-    // Add 4 concurrent senders
-    for _ in 0..4 {
-        {
-            let msg_queue = msg_queue.clone();
-            let push_dict = push_dict.clone();
-            let connected = connected.clone();
-            tokio::spawn(async move {
-                send_loop(msg_queue, push_dict, connected).await;
-            });
+// 关闭服务端的几个阶段：先通知所有连接发 Quit 退出，等太久的话让 write_loop 不要再写了
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Phase {
+    Running,
+    Closing,
+    Aborted,
+}
+
+// 登录后的一个连接
+struct Client {
+    outbox: Outbox, // 交给这个连接的 write_loop, 所有 Sender 都 drop 之后 write_loop 关掉 writer
+    closed: Arc<Notify>, // 被踢下线、被顶掉或者写失败时通知 recv_loop 退出
+    evict: Arc<Notify>, // 连接已经断了，通知 write_loop 不要再写，剩下的消息都存到离线消息里
+}
+
+// 所有连接共享的状态
+#[derive(Clone)]
+struct Shared {
+    config: Arc<ServerConfig>,
+    ids: Arc<IdAllocator>,
+    recent_sends: Arc<Sharded<SendDedup>>,
+    state: State,
+    reaction_dict: ReactionDict,
+    muted: Muted,
+    users: Users,
+    history: Arc<Mutex<History>>,
+    profiles: Arc<Mutex<Profiles>>,
+    accounts: Arc<Mutex<Accounts>>,
+    sessions: Arc<Mutex<Sessions>>,
+    // 每条消息、每一帧都要查，很少修改
+    blocks: Arc<RwLock<BlockList>>,
+    contacts: Arc<RwLock<Contacts>>,
+    bans: Arc<RwLock<BanList>>,
+    rate_limits: RateLimits,
+    ip_conns: ConnLimiter<IpAddr>,
+    user_conns: ConnLimiter<u64>,
+    hashing: Arc<Semaphore>,          // 同时最多算几个 argon2
+    shutdown: watch::Receiver<Phase>, // 服务端要关闭时通知所有连接退出，等太久了通知 write_loop 不要再写
+    drained: mpsc::Sender<()>,        // 只用来等所有连接和 write_loop 退出，都 drop 了才算完
+}
+
+#[tokio::main]
+async fn main() {
+    let config = ServerConfig::from_env();
+    if let Some(dir) = &config.data_dir {
+        std::fs::create_dir_all(dir).unwrap();
+    }
+    let ids =
+        Arc::new(IdAllocator::new(config.data_file("msg_id"), config.time_ordered_ids).unwrap());
+    let history = History::open(config.data_file("history")).unwrap();
+    let profiles = config.data_file("profiles");
+    let accounts = Accounts::open(config.data_file("accounts")).unwrap();
+    for &admin in &config.admins {
+        if !accounts.exists(admin) {
+            eprintln!("Admin {} has no account and can not register, start without it in MY_CHAT_ADMINS to register first", admin);
         }
     }
+    // 重启之后用户列表和广播也要知道之前的用户，不用等他们再登录一次
+    // 只看注册过的账号，历史消息里的 to 是发送者随便填的，不一定是真的用户
+    let users: BTreeSet<u64> = accounts.users().collect();
+    let session_ttl = config.session_ttl as i64;
+    let blocks = config.data_file("blocks");
+    let contacts = config.data_file("contacts");
+    let bans = config.data_file("bans");
+    let max_conns = match config.max_conns as usize {
+        0 => Semaphore::MAX_PERMITS,
+        n => n,
+    };
+    let ip_conns = ConnLimiter::new(config.max_conns_per_ip as usize);
+    let user_conns = ConnLimiter::new(config.max_conns_per_user as usize);
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let shards = cpus * SHARDS_PER_CPU;
+    // 离线消息的 cursor 单独计数，不占 msg_id
+    let cursors = Arc::new(IdAllocator::new(config.data_file("offline_cursor"), false).unwrap());
+    let state = Arc::new(ServerState::new(shards, cursors));
+    // 上次关闭时没投递的离线消息
+    if let Some(path) = config.data_file("offline") {
+        let n = state.load_offline(&path).unwrap();
+        if n > 0 {
+            eprintln!("Restored {} offline messages", n);
+        }
+    }
+    let (shutdown_tx, shutdown_rx) = watch::channel(Phase::Running);
+    let (drained, drained_rx) = mpsc::channel(1);
+    let shared = Shared {
+        config: Arc::new(config),
+        ids,
+        recent_sends: Arc::new(Sharded::new(shards, || {
+            SendDedup::new(DEDUP_TTL, DEDUP_CAPACITY.div_ceil(shards))
+        })),
+        state,
+        reaction_dict: Arc::new(Sharded::new(shards, || {
+            RecentReactions::new(REACTION_CAPACITY.div_ceil(shards))
+        })),
+        muted: Arc::new(Sharded::new(shards, HashMap::new)),
+        users: Arc::new(Mutex::new(users)),
+        history: Arc::new(Mutex::new(history)),
+        profiles: Arc::new(Mutex::new(Profiles::open(profiles).unwrap())),
+        accounts: Arc::new(Mutex::new(accounts)),
+        sessions: Arc::new(Mutex::new(Sessions::new(session_ttl))),
+        blocks: Arc::new(RwLock::new(BlockList::open(blocks).unwrap())),
+        contacts: Arc::new(RwLock::new(Contacts::open(contacts).unwrap())),
+        bans: Arc::new(RwLock::new(BanList::open(bans).unwrap())),
+        rate_limits: Arc::new(Sharded::new(shards, HashMap::new)),
+        ip_conns,
+        user_conns,
+        hashing: Arc::new(Semaphore::new(cpus)),
+        shutdown: shutdown_rx,
+        drained,
+    };
+    let listener = TcpListener::bind(&shared.config.addr).await.unwrap();
 
-    // TODO: 这里没有对 socket 上限进行限制
+    {
+        let state = shared.state.clone();
+        let sessions = shared.sessions.clone();
+        let rate_limits = shared.rate_limits.clone();
+        tokio::spawn(async move {
+            expire_loop(state, sessions, rate_limits).await;
+        });
+    }
+
+    let conns = Arc::new(Semaphore::new(max_conns));
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
     loop {
-        let msg_queue = msg_queue.clone();
-        let push_dict = push_dict.clone();
-        let connected = connected.clone();
-        let (socket, _) = listener.accept().await.unwrap();
+        let (permit, socket, addr) = tokio::select! {
+            res = accept(&listener, &conns) => res,
+            _ = &mut signal => break,
+        };
+        let shared = shared.clone();
         tokio::spawn(async move {
-            recv_loop(socket, msg_queue, push_dict, connected).await;
+            let _permit = permit;
+            match shared.ip_conns.try_acquire(addr.ip()) {
+                Some(_guard) => recv_loop(socket, addr, shared).await,
+                None => refuse(socket).await,
+            }
         });
     }
+    drop(listener);
+    shutdown(shared, shutdown_tx, drained_rx).await;
 }
 
-async fn recv_loop(
-    socket: TcpStream,
-    msg_queue: MsgQueue,
-    push_dict: PushDict,
-    connected: Connected,
-) {
+// 连接数满了就先不 accept, 新连接留在内核的 backlog 里等着
+async fn accept(
+    listener: &TcpListener,
+    conns: &Arc<Semaphore>,
+) -> (OwnedSemaphorePermit, TcpStream, SocketAddr) {
+    let permit = conns.clone().acquire_owned().await.unwrap();
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => return (permit, socket, addr),
+            Err(e) => {
+                // 比如文件描述符用完了，等一会儿再试，不要让服务端挂掉
+                eprintln!("Failed to accept: {}", e);
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+// 等到服务端开始关闭，已经开始了的话马上返回
+async fn shutting_down(shutdown: &mut watch::Receiver<Phase>) {
+    let _ = shutdown.wait_for(|&phase| phase >= Phase::Closing).await;
+}
+
+// write_loop 不要再写了：连接已经断了，或者关闭服务端时等太久了
+async fn evicted(evict: &Notify, shutdown: &mut watch::Receiver<Phase>) {
+    tokio::select! {
+        _ = evict.notified() => (),
+        _ = shutdown.wait_for(|&phase| phase == Phase::Aborted) => (),
+    }
+}
+
+// Ctrl-C 或者 SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = term.recv() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+// 已经不再 accept 了：通知所有连接发 Quit 退出，等 write_loop 把 outbox 里的消息写完，最多等 SHUTDOWN_TIMEOUT
+// 超时了就让 write_loop 不要再写，outbox 里剩下的消息都挪到离线消息里
+// 等历史消息都写进文件，配置了 data_dir 的话把还没 ack 的离线消息存起来，下次启动时恢复
+async fn shutdown(shared: Shared, notify: watch::Sender<Phase>, mut drained: mpsc::Receiver<()>) {
+    eprintln!("Shutting down");
+    let _ = notify.send(Phase::Closing);
+    let state = shared.state.clone();
+    let history = shared.history.clone();
+    let path = shared.config.data_file("offline");
+    drop(shared);
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, drained.recv())
+        .await
+        .is_err()
+    {
+        eprintln!(
+            "Connections not drained in {} seconds, save the rest as offline messages",
+            SHUTDOWN_TIMEOUT.as_secs()
+        );
+        // 不再写 socket, 只是挪到离线消息里，很快就能结束
+        let _ = notify.send(Phase::Aborted);
+        if tokio::time::timeout(ABORT_TIMEOUT, drained.recv())
+            .await
+            .is_err()
+        {
+            eprintln!("Connections still not drained, exit anyway");
+        }
+    }
+    history.lock().unwrap().close();
+    if let Some(path) = path {
+        match state.save_offline(&path) {
+            Ok(n) => eprintln!("Saved {} offline messages", n),
+            Err(e) => eprintln!("Failed to save offline messages: {}", e),
+        }
+    }
+}
+
+async fn recv_loop(socket: TcpStream, addr: SocketAddr, shared: Shared) {
     //let mut conn = Connection::<Msg2S>::new(BufReader::new(socket));
 
     //let (mut reader, mut writer) = socket.split();
@@ -60,139 +290,1375 @@ async fn recv_loop(
     let mut conn = Connection::<Msg2S>::new(BufReader::new(reader));
     let mut writer = BufWriter::new(writer);
     let mut login_user_id = 0u64;
-    let mut message_id = 0u64; // 没有数据库，都从 0 开始吧
+    let now = Instant::now();
+    let config = &shared.config;
+    let mut limiter = RateLimiter::new(config.conn_rate_msgs, config.conn_rate_bytes, now);
+    let mut strikes = TokenBucket::new(RATE_LIMIT_FORGIVE, RATE_LIMIT_STRIKES, now);
+    let mut _user_guard: Option<ConnGuard<u64>> = None; // 连接断开时 drop, 用户的连接数减一
+    let mut session = String::new(); // 这个连接用的 token, Logout 时只让它失效
+    let closed = Arc::new(Notify::new());
+    let mut shutdown = shared.shutdown.clone();
 
     // 理论上应该先验证登录，而不是直接解析，这样可以防止匿名长消息攻击
-    // TODO: 用户断网（不同时长）时重连的处理，都需要重新登录好像不 ergonomic
-    while let Some(msg) = conn.read_frame().await.unwrap() {
-        dbg!(&msg);
-        if let Msg2S::Login { user_id } = msg {
-            // TODO: 本来应该判断，user_id 有没有对应的 client，做一些处理
-            // 不过这样写太复杂了，以后再说，下面的 login 同理
-            connected.lock().unwrap().insert(user_id, Some(writer));
-            login_user_id = user_id;
-            break;
-        } else {
-            writer
-                .write_all(&Msg2C::AuthRequired.to_bytes())
-                .await
-                .unwrap();
-            writer.flush().await.unwrap();
+    // 断网重连时客户端用 Resume 带上之前的 token, 不用再输密码
+    let idle_timeout = shared.config.idle_timeout;
+    while let Some(msg) = tokio::select! {
+        msg = read_frame(&mut conn, idle_timeout) => msg,
+        _ = shutting_down(&mut shutdown) => {
+            goodbye(&mut writer, ErrCode::ShuttingDown).await;
+            return;
+        }
+    } {
+        if !msg.has_secret() {
+            dbg!(&msg);
+        }
+        if !allow(&shared, &mut limiter, 0, conn.frame_len()) {
+            if !strikes.take(1.0, Instant::now()) {
+                return;
+            }
+            let reply = Msg2C::Err {
+                code: ErrCode::RateLimited,
+            };
+            if writer.write_all(&reply.to_bytes()).await.is_err() || writer.flush().await.is_err() {
+                return;
+            }
+            continue;
         }
+        let reply = match msg {
+            Msg2S::Login { user_id, .. } if shared.bans.read().unwrap().is_banned(user_id) => {
+                Msg2C::Err {
+                    code: ErrCode::Banned,
+                }
+            }
+            Msg2S::Login { user_id, password } => match acquire_user(&shared, user_id) {
+                Some(guard) => {
+                    if let Err(code) = authenticate(&shared, user_id, password).await {
+                        Msg2C::Err { code }
+                    } else {
+                        let (token, expire_at) = shared
+                            .sessions
+                            .lock()
+                            .unwrap()
+                            .issue(user_id, get_current_timestamp());
+                        let session_msg = Msg2C::Session {
+                            token: token.clone(),
+                            expire_at,
+                        };
+                        match online(&shared, user_id, addr, writer, closed.clone(), session_msg) {
+                            Ok(()) => {
+                                _user_guard = Some(guard);
+                                session = token;
+                                login_user_id = user_id;
+                                break;
+                            }
+                            Err(w) => {
+                                writer = w;
+                                shared.sessions.lock().unwrap().revoke(&token);
+                                Msg2C::Err {
+                                    code: ErrCode::AlreadyOnline,
+                                }
+                            }
+                        }
+                    }
+                }
+                None => Msg2C::Err {
+                    code: ErrCode::Busy,
+                },
+            },
+            Msg2S::Resume { token } => {
+                let resumed = shared
+                    .sessions
+                    .lock()
+                    .unwrap()
+                    .resume(&token, get_current_timestamp());
+                match resumed {
+                    Some((user_id, expire_at)) => match acquire_user(&shared, user_id) {
+                        Some(guard) => {
+                            let session_msg = Msg2C::Session {
+                                token: token.clone(),
+                                expire_at,
+                            };
+                            match online(
+                                &shared,
+                                user_id,
+                                addr,
+                                writer,
+                                closed.clone(),
+                                session_msg,
+                            ) {
+                                Ok(()) => {
+                                    _user_guard = Some(guard);
+                                    // 断线期间没发出去的消息都在离线消息里，客户端接着之前的 cursor pull 就行
+                                    session = token;
+                                    login_user_id = user_id;
+                                    break;
+                                }
+                                Err(w) => {
+                                    writer = w;
+                                    Msg2C::Err {
+                                        code: ErrCode::AlreadyOnline,
+                                    }
+                                }
+                            }
+                        }
+                        None => Msg2C::Err {
+                            code: ErrCode::Busy,
+                        },
+                    },
+                    None => Msg2C::Err {
+                        code: ErrCode::AuthFailed,
+                    },
+                }
+            }
+            Msg2S::Register { user_id, password } => register(&shared, user_id, password).await,
+            Msg2S::Logout => {
+                let _ = writer.write_all(&Msg2C::Quit.to_bytes()).await;
+                let _ = writer.flush().await;
+                return;
+            }
+            Msg2S::Beat => continue, // 客户端登录前也会定时发，只是保活
+            _ => Msg2C::AuthRequired,
+        };
+        // 客户端可能已经断开了，写不出去就当作断开，不要 panic
+        if writer.write_all(&reply.to_bytes()).await.is_err() || writer.flush().await.is_err() {
+            return;
+        }
+    }
+    if login_user_id == 0 {
+        return; // 还没登录就断开了
     }
 
-    while let Some(msg) = conn.read_frame().await.unwrap() {
-        dbg!(&msg);
+    // 被踢下线、被顶掉或者 write_loop 写失败了，和读到连接断开一样处理
+    while let Some(msg) = tokio::select! {
+        msg = read_frame(&mut conn, idle_timeout) => msg,
+        _ = closed.notified() => None,
+        _ = shutting_down(&mut shutdown) => {
+            // 服务端要关闭了，不用再通知其他人下线
+            let client = shared.state.connected(login_user_id).remove(login_user_id, addr);
+            if let Some(client) = client {
+                close(client, Some(ErrCode::ShuttingDown));
+            }
+            return;
+        }
+    } {
+        if !msg.has_secret() {
+            dbg!(&msg);
+        }
+        // 被封禁时 writer 已经关掉了，不听话的客户端还在发的话直接断开
+        if shared.bans.read().unwrap().is_banned(login_user_id) {
+            return;
+        }
+        if !allow(&shared, &mut limiter, login_user_id, conn.frame_len()) {
+            if !strikes.take(1.0, Instant::now()) {
+                eprintln!(
+                    "User {} keeps exceeding the rate limit, disconnect",
+                    login_user_id
+                );
+                // 只断开这个连接，同一个用户的其他设备不受影响
+                shared.sessions.lock().unwrap().revoke(&session);
+                logout(&shared, login_user_id, addr, Some(ErrCode::RateLimited));
+                return;
+            }
+            respond(
+                &shared,
+                login_user_id,
+                addr,
+                Msg2C::Err {
+                    code: ErrCode::RateLimited,
+                },
+            );
+            continue;
+        }
         match msg {
             Msg2S::Msg {
                 fake_msg_id,
                 to,
-                len,
+                ttl,
                 msg,
+                ..
             } => {
-                let mut mq = msg_queue.lock().unwrap();
-                message_id += 1;
-
-                mq.push_back((
+                deliver(&shared, login_user_id, addr, fake_msg_id, &[to], ttl, msg);
+            }
+            Msg2S::MultiMsg {
+                fake_msg_id,
+                to,
+                ttl,
+                msg,
+            } => {
+                if to.len() > MULTI_MSG_LIMIT && !shared.config.is_admin(login_user_id) {
+                    respond(
+                        &shared,
+                        login_user_id,
+                        addr,
+                        Msg2C::Err {
+                            code: ErrCode::Invalid,
+                        },
+                    );
+                    continue;
+                }
+                deliver(&shared, login_user_id, addr, fake_msg_id, &to, ttl, msg);
+            }
+            Msg2S::Broadcast {
+                fake_msg_id,
+                ttl,
+                msg,
+            } => {
+                if shared.config.is_admin(login_user_id) {
+                    let to: Vec<u64> = shared
+                        .users
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .copied()
+                        .filter(|&user_id| user_id != login_user_id)
+                        .collect();
+                    deliver(&shared, login_user_id, addr, fake_msg_id, &to, ttl, msg);
+                } else {
+                    respond(
+                        &shared,
+                        login_user_id,
+                        addr,
+                        Msg2C::Err {
+                            code: ErrCode::Forbidden,
+                        },
+                    );
+                }
+            }
+            Msg2S::Login { user_id, .. } if shared.bans.read().unwrap().is_banned(user_id) => {
+                respond(
+                    &shared,
                     login_user_id,
-                    Msg2C::Update {
-                        fake_msg_id,
-                        real_msg_id: message_id,
+                    addr,
+                    Msg2C::Err {
+                        code: ErrCode::Banned,
+                    },
+                );
+            }
+            Msg2S::Login { user_id, password } => {
+                // 换了用户的话这个连接要算到新用户头上
+                let guard = if user_id == login_user_id {
+                    None
+                } else {
+                    match acquire_user(&shared, user_id) {
+                        Some(guard) => Some(guard),
+                        None => {
+                            respond(
+                                &shared,
+                                login_user_id,
+                                addr,
+                                Msg2C::Err {
+                                    code: ErrCode::Busy,
+                                },
+                            );
+                            continue;
+                        }
                     }
-                    .to_bytes(),
-                ));
-
-                mq.push_back((
-                    to,
-                    Msg2C::Msg {
-                        msg_id: message_id,
-                        from: login_user_id,
-                        ts: get_current_timestamp(),
-                        len,
-                        msg,
+                };
+                if let Err(code) = authenticate(&shared, user_id, password).await {
+                    respond(&shared, login_user_id, addr, Msg2C::Err { code });
+                    continue;
+                }
+                if user_id != login_user_id {
+                    if let Err(code) = switch_user(&shared, login_user_id, user_id, addr) {
+                        respond(&shared, login_user_id, addr, Msg2C::Err { code });
+                        continue;
                     }
-                    .to_bytes(),
-                ));
-            }
-            Msg2S::Login { user_id } => {
-                if let Some(client) = connected.lock().unwrap().remove(&user_id) {
-                    connected.lock().unwrap().insert(login_user_id, client);
+                    _user_guard = guard;
                 }
-                //connected.lock().unwrap().insert
-                //connected.lock().unwrap().insert(user_id, Some(writer));
+                // 旧的 token 失效，相当于这个连接退出了之前的账号
+                shared.sessions.lock().unwrap().revoke(&session);
+                let (token, expire_at) = shared
+                    .sessions
+                    .lock()
+                    .unwrap()
+                    .issue(user_id, get_current_timestamp());
+                session = token.clone();
                 login_user_id = user_id;
+                let frames = [Msg2C::Ok, Msg2C::Session { token, expire_at }];
+                send_direct(&shared, user_id, addr, frames);
+            }
+            Msg2S::Resume { .. } => {
+                // 已经登录了，切换用户请用 Login
+                respond(
+                    &shared,
+                    login_user_id,
+                    addr,
+                    Msg2C::Err {
+                        code: ErrCode::Invalid,
+                    },
+                );
             }
-            Msg2S::Pull => {
-                // NOTE: 其他地方锁了 push_dict 的话都是在插数据，不会 take out
-                if let Some(pq) = push_dict.lock().unwrap().remove(&login_user_id) {
-                    let mut mq = msg_queue.lock().unwrap();
-                    for msg in pq {
-                        mq.push_back((login_user_id, msg));
+            Msg2S::Register { user_id, password } => {
+                let reply = register(&shared, user_id, password).await;
+                respond(&shared, login_user_id, addr, reply);
+            }
+            Msg2S::React { msg_id, emoji } => {
+                react(&shared, login_user_id, addr, msg_id, &emoji, true);
+            }
+            Msg2S::Unreact { msg_id, emoji } => {
+                react(&shared, login_user_id, addr, msg_id, &emoji, false);
+            }
+            Msg2S::Mute { peer } => {
+                shared
+                    .muted
+                    .lock(login_user_id)
+                    .entry(login_user_id)
+                    .or_default()
+                    .insert(peer);
+            }
+            Msg2S::Unmute { peer } => {
+                if let Some(peers) = shared.muted.lock(login_user_id).get_mut(&login_user_id) {
+                    peers.remove(&peer);
+                }
+            }
+            Msg2S::Block { peer } | Msg2S::Unblock { peer } => {
+                let blocked = matches!(msg, Msg2S::Block { .. });
+                let res = shared
+                    .blocks
+                    .write()
+                    .unwrap()
+                    .set(login_user_id, peer, blocked);
+                let reply = match res {
+                    Ok(changed) => {
+                        // 对方看到的在线状态马上跟着变：屏蔽后显示为离线，取消屏蔽后恢复成当前状态
+                        // 自己不在线的话对方看到的本来就是离线
+                        if changed && peer != login_user_id && shared.state.is_online(login_user_id)
+                        {
+                            let presence = Msg2C::Presence {
+                                user_id: login_user_id,
+                                online: !blocked,
+                            };
+                            send(&shared, peer, presence);
+                        }
+                        Msg2C::Ok
                     }
+                    Err(e) => {
+                        eprintln!("Failed to save block list: {}", e);
+                        Msg2C::Err {
+                            code: ErrCode::Unknown,
+                        }
+                    }
+                };
+                respond(&shared, login_user_id, addr, reply);
+            }
+            Msg2S::ListBlocked => {
+                let users = shared.blocks.read().unwrap().list(login_user_id);
+                respond(&shared, login_user_id, addr, Msg2C::Blocked { users });
+            }
+            Msg2S::AddContact { .. }
+            | Msg2S::AcceptContact { .. }
+            | Msg2S::DeclineContact { .. }
+            | Msg2S::RemoveContact { .. }
+            | Msg2S::ContactsOnly { .. } => {
+                contact(&shared, login_user_id, addr, msg);
+            }
+            Msg2S::ListContacts => {
+                let frame = {
+                    let contacts = shared.contacts.read().unwrap();
+                    Msg2C::Contacts {
+                        contacts: contacts.contacts(login_user_id),
+                        requests: contacts.requests(login_user_id),
+                        contacts_only: contacts.is_contacts_only(login_user_id),
+                    }
+                };
+                respond(&shared, login_user_id, addr, frame);
+            }
+            Msg2S::Pull {
+                since_msg_id,
+                limit,
+            } => {
+                let limit = if limit == 0 {
+                    PULL_LIMIT
+                } else {
+                    limit.min(PULL_LIMIT)
+                };
+                let (page, has_more) = shared.state.offline(login_user_id).page(
+                    login_user_id,
+                    since_msg_id,
+                    limit as usize,
+                );
+                // 只是拷贝，ack 之前消息还留在离线消息里
+                let last_msg_id = page.last().map_or(since_msg_id, |(cursor, _)| *cursor);
+                let now = get_current_timestamp();
+                let msgs = page
+                    .into_iter()
+                    .map(|(_, msg)| msg)
+                    .filter(|msg| !msg.is_expired(now))
+                    .collect();
+                respond(
+                    &shared,
+                    login_user_id,
+                    addr,
+                    Msg2C::Page {
+                        last_msg_id,
+                        has_more,
+                        msgs,
+                    },
+                );
+            }
+            Msg2S::Ack { msg_id } => {
+                shared
+                    .state
+                    .offline(login_user_id)
+                    .ack(login_user_id, msg_id);
+            }
+            Msg2S::Search {
+                query,
+                whole_word,
+                sender,
+                peer,
+                since_ts,
+                until_ts,
+                before_msg_id,
+                limit,
+            } => {
+                let query = Query {
+                    text: query,
+                    whole_word,
+                    sender,
+                    peer,
+                    since_ts,
+                    until_ts,
+                };
+                let limit = if limit == 0 {
+                    HISTORY_LIMIT
+                } else {
+                    limit.min(HISTORY_LIMIT)
+                };
+                // 只在锁里取出这个用户的会话，搜索 (要转小写，逐条比较) 放到锁外面做
+                let convs = shared.history.lock().unwrap().conversations(login_user_id);
+                let (records, has_more) =
+                    convs.search(login_user_id, &query, before_msg_id, limit as usize);
+                respond(
+                    &shared,
+                    login_user_id,
+                    addr,
+                    Msg2C::SearchResult { has_more, records },
+                );
+            }
+            Msg2S::History {
+                peer,
+                before_msg_id,
+                limit,
+            } => {
+                let limit = if limit == 0 {
+                    HISTORY_LIMIT
+                } else {
+                    limit.min(HISTORY_LIMIT)
+                };
+                let (records, has_more) = shared.history.lock().unwrap().page(
+                    login_user_id,
+                    peer,
+                    before_msg_id,
+                    limit as usize,
+                );
+                respond(
+                    &shared,
+                    login_user_id,
+                    addr,
+                    Msg2C::History {
+                        peer,
+                        has_more,
+                        records,
+                    },
+                );
+            }
+            Msg2S::Directory {
+                after_user_id,
+                online_only,
+                limit,
+            } => {
+                let limit = if limit == 0 {
+                    DIRECTORY_LIMIT
+                } else {
+                    limit.min(DIRECTORY_LIMIT)
+                };
+                let (users, has_more) = directory(
+                    &shared,
+                    login_user_id,
+                    after_user_id,
+                    online_only,
+                    limit as usize,
+                );
+                respond(
+                    &shared,
+                    login_user_id,
+                    addr,
+                    Msg2C::Directory { has_more, users },
+                );
+            }
+            Msg2S::SetProfile { name, status } => {
+                let reply = if profile::is_valid(&name, &status) {
+                    let profile = Profile {
+                        user_id: login_user_id,
+                        name,
+                        status,
+                    };
+                    match shared.profiles.lock().unwrap().set(profile.clone()) {
+                        Ok(_) => Msg2C::Profile { profile },
+                        Err(e) => {
+                            eprintln!("Failed to save profile: {}", e);
+                            Msg2C::Err {
+                                code: ErrCode::Unknown,
+                            }
+                        }
+                    }
+                } else {
+                    Msg2C::Err {
+                        code: ErrCode::Invalid,
+                    }
+                };
+                respond(&shared, login_user_id, addr, reply);
+            }
+            Msg2S::GetProfile { user_id } => {
+                let profile = shared.profiles.lock().unwrap().get(user_id);
+                respond(&shared, login_user_id, addr, Msg2C::Profile { profile });
+            }
+            Msg2S::Logout => {
+                // 只退出这个连接，其他设备不受影响，之后还没发的消息都会存到离线消息里
+                shared.sessions.lock().unwrap().revoke(&session);
+                logout(&shared, login_user_id, addr, None);
+                return;
+            }
+            Msg2S::Kick { .. }
+            | Msg2S::Ban { .. }
+            | Msg2S::Unban { .. }
+            | Msg2S::Announce { .. }
+            | Msg2S::Stats => {
+                let reply = if shared.config.is_admin(login_user_id) {
+                    admin(&shared, login_user_id, msg)
+                } else {
+                    Some(Msg2C::Err {
+                        code: ErrCode::Forbidden,
+                    })
+                };
+                if let Some(reply) = reply {
+                    respond(&shared, login_user_id, addr, reply);
                 }
             }
             Msg2S::Beat => {
-                // do nothing
+                // do nothing, 只是更新了活跃时间
             }
         }
     }
+    disconnect(&shared, login_user_id, addr);
 }
 
-async fn send_loop(msg_queue: MsgQueue, push_dict: PushDict, connected: Connected) {
-    // push_dict 会在这里添加，会在用户上线时减少
-    // connected 会在这里减少（发送失败时），会在用户登录时增加
-    // 没有两个同时 lock，所以不会造成死锁
-    loop {
-        let first = msg_queue.lock().unwrap().pop_front();
-        // if let Some((user_id, msg)) = msg_queue.lock().unwrap().pop_front()
-        // 会被认为 block 内还会锁着 msg_queue, rust 还是不够智能
+// 连接出错或者超过 idle_timeout 秒 (0 表示不限制) 没收到任何帧都返回 None, 当作断开处理
+// 不这样的话对面断网了 (半开连接) 要等到写失败才会发现
+async fn read_frame(conn: &mut Connection<Msg2S>, idle_timeout: u64) -> Option<Msg2S> {
+    let res = if idle_timeout == 0 {
+        conn.read_frame().await
+    } else {
+        let timeout = std::time::Duration::from_secs(idle_timeout);
+        match tokio::time::timeout(timeout, conn.read_frame()).await {
+            Ok(res) => res,
+            Err(_) => {
+                eprintln!("Connection idle for {} seconds, disconnect", idle_timeout);
+                return None;
+            }
+        }
+    };
+    res.unwrap_or_else(|e| {
+        eprintln!("Failed to read frame: {}", e);
+        None
+    })
+}
 
-        if first.is_none() {
-            // no data in msg_queue
-            tokio::time::sleep(std::time::Duration::from_millis(250)).await;
-        } else {
-            let (user_id, msg) = first.unwrap();
+// 主动退出或者被断开的一个连接：发完 outbox 里的消息再关掉，没有其他连接的话通知其他人下线了
+fn logout(shared: &Shared, user_id: u64, addr: SocketAddr, reason: Option<ErrCode>) {
+    let removed = shared.state.connected(user_id).remove(user_id, addr);
+    if let Some(client) = removed {
+        close(client, reason);
+        if !shared.state.is_online(user_id) {
+            publish_presence(shared, user_id, false);
+        }
+    }
+}
 
-            loop {
-                if connected.lock().unwrap().get(&user_id).is_none() {
-                    // 离线
-                    (*push_dict.lock().unwrap().entry(user_id).or_insert(vec![])).push(msg);
-                } else {
-                    let mut take_out = None::<BufWriter<OwnedWriteHalf>>;
-                    std::mem::swap(
-                        &mut take_out,
-                        connected.lock().unwrap().get_mut(&user_id).unwrap(),
-                    );
-                    if let Some(mut client) = take_out {
-                        // TODO: 错误处理，什么时候需要再试，
-                        // 什么时候要删掉 client, 并加到 push_dict 中
-                        let mut succeed = true;
-                        if client.write_all(&msg).await.is_err() {
-                            succeed = false;
-                        }
-                        if succeed && client.flush().await.is_err() {
-                            succeed = false;
-                        }
+// 连接断开了：移除这个连接，没有其他连接的话通知其他人下线了 (被踢下线或者被顶掉时已经移除了)
+// 对面可能已经不在了 (半开连接), 写进去也收不到，所以 outbox 里剩下的消息都存到离线消息里
+// 之后发给他的消息也都存到离线消息里, token 不失效，可以 Resume
+fn disconnect(shared: &Shared, user_id: u64, addr: SocketAddr) {
+    let removed = shared.state.connected(user_id).remove(user_id, addr);
+    if let Some(client) = removed {
+        client.evict.notify_one();
+        if !shared.state.is_online(user_id) {
+            publish_presence(shared, user_id, false);
+        }
+    }
+}
 
-                        if succeed {
-                            // 换回去，注意这个时候 client 一定是存在的，因为只能在此处删除
-                            std::mem::swap(
-                                &mut Some(client),
-                                connected.lock().unwrap().get_mut(&user_id).unwrap(),
-                            );
-                        } else {
-                            // 移除
-                            connected.lock().unwrap().remove(&user_id);
-                            (*push_dict.lock().unwrap().entry(user_id).or_insert(vec![])).push(msg);
-                        }
-                    } else {
-                        // 使用中, async wait
-                        tokio::time::sleep(std::time::Duration::from_millis(250)).await;
-                        continue;
-                    };
+// 管理员命令，调用前已经检查过权限了
+fn admin(shared: &Shared, user_id: u64, msg: Msg2S) -> Option<Msg2C> {
+    let reply = match msg {
+        // 踢自己请用 Logout
+        Msg2S::Kick { user_id: target } | Msg2S::Ban { user_id: target } if target == user_id => {
+            Msg2C::Err {
+                code: ErrCode::Invalid,
+            }
+        }
+        Msg2S::Kick { user_id: target } => {
+            if kick(shared, target, None) {
+                Msg2C::Ok
+            } else {
+                Msg2C::Err {
+                    code: ErrCode::NotFound,
                 }
+            }
+        }
+        Msg2S::Ban { user_id: target } | Msg2S::Unban { user_id: target } => {
+            let banned = matches!(msg, Msg2S::Ban { .. });
+            let res = shared.bans.write().unwrap().set(target, banned);
+            match res {
+                Ok(_) => {
+                    if banned {
+                        kick(shared, target, Some(ErrCode::Banned));
+                    }
+                    Msg2C::Ok
+                }
+                Err(e) => {
+                    eprintln!("Failed to save ban list: {}", e);
+                    Msg2C::Err {
+                        code: ErrCode::Unknown,
+                    }
+                }
+            }
+        }
+        Msg2S::Announce { msg } => {
+            // 只发给在线用户，包括自己，所以不用再回复 Ok
+            let to = shared.state.online_users();
+            for id in to {
+                let frame = Msg2C::Announce {
+                    from: user_id,
+                    msg: msg.clone(),
+                };
+                send(shared, id, frame);
+            }
+            return None;
+        }
+        Msg2S::Stats => {
+            // 所有连接的 outbox 里还没写出去的消息数
+            let (connected, msg_queue) = shared
+                .state
+                .connections(|client| OUTBOX_CAPACITY - client.outbox.capacity());
+            let users = shared.users.lock().unwrap().len() as u64;
+            let (offline_users, offline_msgs) = shared.state.offline_stats();
+            let banned = shared.bans.read().unwrap().list();
+            Msg2C::Stats {
+                connected: connected as u64,
+                users,
+                msg_queue: msg_queue as u64,
+                offline_users: offline_users as u64,
+                offline_msgs: offline_msgs as u64,
+                banned,
+            }
+        }
+        _ => unreachable!(),
+    };
+    Some(reply)
+}
+
+// 让 user_id 所有的 token 失效并关掉他所有的连接，reason 会在 Quit 之前告诉客户端，返回他是否在线
+fn kick(shared: &Shared, user_id: u64, reason: Option<ErrCode>) -> bool {
+    shared.sessions.lock().unwrap().revoke_user(user_id);
+    let clients = shared.state.connected(user_id).remove_user(user_id);
+    if clients.is_empty() {
+        return false;
+    }
+    for client in clients {
+        close(client, reason);
+    }
+    publish_presence(shared, user_id, false);
+    true
+}
+
+// 已经从 connected 里移除的连接：最后发一个 Quit, drop 掉 outbox 让 write_loop 写完就关掉 writer, 再通知 recv_loop 退出
+// outbox 满了的话 Quit 就不发了，反正连接也要断开
+fn close(client: Client, reason: Option<ErrCode>) {
+    if let Some(code) = reason {
+        let _ = client.outbox.try_send((0, Msg2C::Err { code }));
+    }
+    let _ = client.outbox.try_send((0, Msg2C::Quit));
+    client.closed.notify_one();
+}
+
+// 还没登录的连接直接写 Err 和 Quit, 然后关闭
+async fn goodbye(writer: &mut Writer, code: ErrCode) {
+    let _ = writer.write_all(&Msg2C::Err { code }.to_bytes()).await;
+    let _ = writer.write_all(&Msg2C::Quit.to_bytes()).await;
+    let _ = writer.flush().await;
+    let _ = writer.shutdown().await;
+}
+
+// 回复 Err Busy 后直接关闭连接
+async fn refuse(socket: TcpStream) {
+    let mut writer = BufWriter::new(socket);
+    let reply = Msg2C::Err {
+        code: ErrCode::Busy,
+    };
+    let _ = writer.write_all(&reply.to_bytes()).await;
+    let _ = writer.flush().await;
+    let _ = writer.shutdown().await;
+}
+
+// 先按连接限流，登录之后 (user_id 不为 0) 再按用户限流
+fn allow(shared: &Shared, limiter: &mut RateLimiter, user_id: u64, len: usize) -> bool {
+    let now = Instant::now();
+    if !limiter.check(len, now) {
+        return false;
+    }
+    if user_id == 0 {
+        return true;
+    }
+    let config = &shared.config;
+    shared
+        .rate_limits
+        .lock(user_id)
+        .entry(user_id)
+        .or_insert_with(|| RateLimiter::new(config.user_rate_msgs, config.user_rate_bytes, now))
+        .check(len, now)
+}
+
+// 登录前占一个这个用户的连接数，满了返回 None
+// KickOld 时登录成功后旧的连接都会被踢掉，不能算在上限里，否则上限为 1 时永远登录不了
+// (旧连接的计数要等它的 recv_loop 退出才减掉，先踢掉也来不及)
+fn acquire_user(shared: &Shared, user_id: u64) -> Option<ConnGuard<u64>> {
+    if shared.config.login_policy == LoginPolicy::KickOld {
+        Some(shared.user_conns.acquire(user_id))
+    } else {
+        shared.user_conns.try_acquire(user_id)
+    }
+}
+
+// 登录或恢复会话成功，按 login_policy 处理这个用户已有的连接，再把 writer 交给这个连接的 write_loop
+// 策略是 reject 并且已经在线的话不登录，把 writer 还回去
+fn online(
+    shared: &Shared,
+    user_id: u64,
+    addr: SocketAddr,
+    writer: Writer,
+    closed: Arc<Notify>,
+    session: Msg2C,
+) -> Result<(), Writer> {
+    let policy = shared.config.login_policy;
+    let replaced = if policy == LoginPolicy::KickOld {
+        shared.state.connected(user_id).remove_user(user_id)
+    } else {
+        vec![]
+    };
+    let first = {
+        let mut connected = shared.state.connected(user_id);
+        if policy == LoginPolicy::RejectNew && connected.is_online(user_id) {
+            return Err(writer);
+        }
+        let (outbox, rx) = mpsc::channel(OUTBOX_CAPACITY);
+        // Ok 和 token 只发给这个连接，多设备登录时其他设备收不到
+        let _ = outbox.try_send((0, Msg2C::Ok));
+        let _ = outbox.try_send((0, session));
+        let evict = Arc::new(Notify::new());
+        let state = shared.state.clone();
+        let shutdown = shared.shutdown.clone();
+        let drained = shared.drained.clone();
+        tokio::spawn(write_loop(
+            writer,
+            rx,
+            state,
+            closed.clone(),
+            evict.clone(),
+            shutdown,
+            drained,
+        ));
+        let client = Client {
+            outbox,
+            closed,
+            evict,
+        };
+        connected.insert(user_id, addr, client) && replaced.is_empty()
+    };
+    shared.users.lock().unwrap().insert(user_id);
+    // 被顶掉的连接不再 Resume, 否则两边会来回互相踢
+    for client in replaced {
+        close(client, Some(ErrCode::Replaced));
+    }
+    if first {
+        publish_presence(shared, user_id, true);
+    }
+    Ok(())
+}
+
+// 已登录的连接切换到 user_id 为 to 的账号 (已经验证过密码了)
+// 和登录一样按 login_policy 处理 to 已有的连接，然后把这个连接从 from 移到 to 下面
+fn switch_user(shared: &Shared, from: u64, to: u64, addr: SocketAddr) -> Result<(), ErrCode> {
+    let policy = shared.config.login_policy;
+    let replaced = if policy == LoginPolicy::KickOld {
+        shared.state.connected(to).remove_user(to)
+    } else {
+        vec![]
+    };
+    let kicked = !replaced.is_empty();
+    let res = if policy == LoginPolicy::RejectNew && shared.state.is_online(to) {
+        Err(ErrCode::AlreadyOnline)
+    } else {
+        // 被踢下线了或者写失败了，连接马上就要断开
+        shared.state.switch(from, to, addr).ok_or(ErrCode::Unknown)
+    };
+    for client in replaced {
+        close(client, Some(ErrCode::Replaced));
+    }
+    let (from_offline, first) = res?;
+    shared.users.lock().unwrap().insert(to);
+    if from_offline {
+        publish_presence(shared, from, false);
+    }
+    if first && !kicked {
+        publish_presence(shared, to, true);
+    }
+    Ok(())
+}
+
+// 只发给 addr 这个连接，多设备登录时同一个用户的其他设备收不到
+fn send_direct(
+    shared: &Shared,
+    user_id: u64,
+    addr: SocketAddr,
+    frames: impl IntoIterator<Item = Msg2C>,
+) {
+    if let Some(client) = shared.state.connected(user_id).get_mut(user_id, addr) {
+        for frame in frames {
+            if let Err(mpsc::error::TrySendError::Full(_)) = client.outbox.try_send((0, frame)) {
+                eprintln!("Outbox of user {} is full, disconnect", user_id);
+                client.closed.notify_one();
                 break;
             }
         }
     }
 }
+
+// 请求的回复只发给发起请求的连接，其他设备没有发过这个请求，收到了反而会弄错状态
+// 和 send_direct 一样写不出去就丢掉，不存离线消息
+fn respond(shared: &Shared, user_id: u64, addr: SocketAddr, msg: Msg2C) {
+    send_direct(shared, user_id, addr, [msg]);
+}
+
+// 按 user_id 找到他所有的连接，交给各自的 write_loop, 一个都没交出去就存到离线消息里
+// outbox 满了说明这个客户端读得太慢，通知 recv_loop 断开，不让他拖慢其他人
+fn send(shared: &Shared, user_id: u64, msg: Msg2C) {
+    let mut delivered = false;
+    if let Some(clients) = shared.state.connected(user_id).clients(user_id) {
+        for client in clients.values() {
+            match client.outbox.try_send((user_id, msg.clone())) {
+                Ok(()) => delivered = true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    eprintln!("Outbox of user {} is full, disconnect", user_id);
+                    client.closed.notify_one();
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => (),
+            }
+        }
+    }
+    if !delivered {
+        push_offline(&shared.state, user_id, msg);
+    }
+}
+
+// 上下线只通知当前在线、并且没有被 user_id 屏蔽的用户
+fn publish_presence(shared: &Shared, user_id: u64, online: bool) {
+    let blocked = shared.blocks.read().unwrap().list(user_id);
+    let to: Vec<u64> = shared
+        .state
+        .online_users()
+        .into_iter()
+        .filter(|&id| id != user_id && !blocked.contains(&id))
+        .collect();
+    for id in to {
+        send(shared, id, Msg2C::Presence { user_id, online });
+    }
+}
+
+// argon2 很慢，每次还要十几 MB 内存，放到 blocking 线程里算，不要卡住 tokio 的工作线程
+// 同时最多算 CPU 个数个，满了直接返回 Busy 不排队，否则一个 IP 就能堆起几百个任务
+async fn hash_job<T: Send + 'static>(
+    shared: &Shared,
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, ErrCode> {
+    let permit = shared
+        .hashing
+        .clone()
+        .try_acquire_owned()
+        .map_err(|_| ErrCode::Busy)?;
+    let res = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        f()
+    })
+    .await
+    .unwrap();
+    Ok(res)
+}
+
+// 密码不对返回 AuthFailed, 正在算的 argon2 太多返回 Busy
+async fn authenticate(shared: &Shared, user_id: u64, password: String) -> Result<(), ErrCode> {
+    let hash = shared.accounts.lock().unwrap().hash(user_id);
+    let hash = hash.ok_or(ErrCode::AuthFailed)?;
+    match hash_job(shared, move || account::verify_password(&hash, &password)).await? {
+        true => Ok(()),
+        false => Err(ErrCode::AuthFailed),
+    }
+}
+
+async fn register(shared: &Shared, user_id: u64, password: String) -> Msg2C {
+    // user_id 为 0 表示没有登录，不能注册
+    if user_id == 0 || !account::is_valid_password(&password) {
+        return Msg2C::Err {
+            code: ErrCode::Invalid,
+        };
+    }
+    if shared.bans.read().unwrap().is_banned(user_id) {
+        return Msg2C::Err {
+            code: ErrCode::Banned,
+        };
+    }
+    // 管理员账号不能在线注册，否则谁先连上来注册谁就拿到了管理员权限
+    // 要先在不带 MY_CHAT_ADMINS 启动时注册好，再加进去
+    if shared.config.is_admin(user_id) {
+        return Msg2C::Err {
+            code: ErrCode::Forbidden,
+        };
+    }
+    if shared.accounts.lock().unwrap().exists(user_id) {
+        return Msg2C::Err {
+            code: ErrCode::Exists,
+        };
+    }
+    let hash = match hash_job(shared, move || account::hash_password(&password)).await {
+        Ok(hash) => hash,
+        Err(code) => return Msg2C::Err { code },
+    };
+    // 算 hash 的时候可能已经被别人注册了，insert 里会再检查一次
+    match shared
+        .accounts
+        .lock()
+        .unwrap()
+        .insert(Account { user_id, hash })
+    {
+        Ok(true) => {
+            shared.users.lock().unwrap().insert(user_id);
+            Msg2C::Ok
+        }
+        Ok(false) => Msg2C::Err {
+            code: ErrCode::Exists,
+        },
+        Err(e) => {
+            eprintln!("Failed to save account: {}", e);
+            Msg2C::Err {
+                code: ErrCode::Unknown,
+            }
+        }
+    }
+}
+
+// 单发、多发和广播都走这里：同一条消息只有一个 real_msg_id, 按接收者分发到各自的连接
+// addr 是发送者的连接，出错只回复给它
+fn deliver(
+    shared: &Shared,
+    from: u64,
+    addr: SocketAddr,
+    fake_msg_id: i64,
+    to: &[u64],
+    ttl: u64,
+    msg: String,
+) {
+    // 同一个接收者写了多次的只发一次
+    let mut seen = HashSet::new();
+    let to: Vec<u64> = to.iter().copied().filter(|&id| seen.insert(id)).collect();
+    // 打开了 contacts_only 的接收者拒收非联系人的消息，管理员的消息除外
+    // 在分配 id 之前检查，全部被拒收的消息不会有 real_msg_id
+    let (to, rejected): (Vec<u64>, Vec<u64>) = if shared.config.is_admin(from) {
+        (to, vec![])
+    } else {
+        let contacts = shared.contacts.read().unwrap();
+        to.into_iter()
+            .partition(|&user_id| contacts.allows(user_id, from))
+    };
+    if !rejected.is_empty() {
+        respond(
+            shared,
+            from,
+            addr,
+            Msg2C::Err {
+                code: ErrCode::NotContact,
+            },
+        );
+        if to.is_empty() {
+            return;
+        }
+    }
+
+    // 先在锁外分配好 id, 写 id 文件失败时不会拿着 recent_sends 的锁，重发的消息会白白用掉一个 id, 不要紧
+    let new_msg_id = match shared.ids.next() {
+        Ok(id) => id,
+        Err(e) => {
+            eprintln!("Failed to allocate msg id: {}", e);
+            respond(
+                shared,
+                from,
+                addr,
+                Msg2C::Err {
+                    code: ErrCode::Unknown,
+                },
+            );
+            return;
+        }
+    };
+    let (msg_id, is_new) =
+        shared
+            .recent_sends
+            .lock(from)
+            .get_or_insert_with(from, fake_msg_id, || new_msg_id);
+    if !is_new {
+        // 客户端重发（比如重连前没收到 Update），只回复原来的 real_msg_id
+        send(
+            shared,
+            from,
+            Msg2C::Update {
+                fake_msg_id,
+                real_msg_id: msg_id,
+            },
+        );
+        return;
+    }
+
+    // 屏蔽了发送者的接收者直接去掉，发送者照常收到 Update, 不知道自己被屏蔽了
+    let to: Vec<u64> = {
+        let blocks = shared.blocks.read().unwrap();
+        to.into_iter()
+            .filter(|&user_id| !blocks.is_blocked(user_id, from))
+            .collect()
+    };
+    let to = &to[..];
+
+    // 广播之类发给太多人的消息不能 react, 否则要记住所有接收者，每次点都要通知所有人
+    if to.len() <= MULTI_MSG_LIMIT {
+        shared
+            .reaction_dict
+            .lock(msg_id)
+            .insert(msg_id, Reactions::new([&[from], to].concat()));
+    }
+
+    // 没静音时消息本身就是通知（客户端会高亮 @ 自己的行）,
+    // 静音时消息只存离线，被 @ 的话再单独推一个 Mention
+    let muted_by: HashSet<u64> = to
+        .iter()
+        .copied()
+        .filter(|&user_id| {
+            shared
+                .muted
+                .lock(user_id)
+                .get(&user_id)
+                .is_some_and(|peers| peers.contains(&from))
+        })
+        .collect();
+    let mentions = parse_mentions(&msg);
+    let ts = get_current_timestamp();
+    if ttl == 0 {
+        // 阅后即焚的消息不进历史
+        let mut history = shared.history.lock().unwrap();
+        for &user_id in to {
+            let record = Record {
+                msg_id,
+                from,
+                to: user_id,
+                ts,
+                msg: msg.clone(),
+            };
+            if let Err(e) = history.push(record) {
+                eprintln!("Failed to save history: {}", e);
+            }
+        }
+    }
+    // ttl 是客户端随便填的，太大的话直接加会溢出，当作永远不会过期
+    let expire_at = match ttl {
+        0 => 0,
+        ttl => i64::try_from(ttl).map_or(i64::MAX, |ttl| ts.saturating_add(ttl)),
+    };
+    let frame = Msg2C::Msg {
+        msg_id,
+        from,
+        ts,
+        expire_at,
+        len: msg.len() as u64,
+        msg: msg.clone(),
+    };
+
+    let mut live = vec![(
+        from,
+        Msg2C::Update {
+            fake_msg_id,
+            real_msg_id: msg_id,
+        },
+    )];
+    for &user_id in to {
+        if muted_by.contains(&user_id) {
+            push_offline(&shared.state, user_id, frame.clone());
+            if user_id != from && mentions.contains(&user_id) {
+                live.push((
+                    user_id,
+                    Msg2C::Mention {
+                        msg_id,
+                        from,
+                        expire_at,
+                        msg: msg.clone(),
+                    },
+                ));
+            }
+        } else {
+            live.push((user_id, frame.clone()));
+        }
+    }
+    for (user_id, frame) in live {
+        send(shared, user_id, frame);
+    }
+}
+
+// 联系人请求、接受、拒绝、删除和 contacts_only 设置，成功回复 Ok 并通知对方
+fn contact(shared: &Shared, user_id: u64, addr: SocketAddr, msg: Msg2S) {
+    let (op, peer) = match msg {
+        Msg2S::AddContact { peer } => (
+            ContactOp::Request {
+                from: user_id,
+                to: peer,
+            },
+            peer,
+        ),
+        Msg2S::AcceptContact { peer } => (ContactOp::Accept { user_id, peer }, peer),
+        Msg2S::DeclineContact { peer } => (ContactOp::Decline { user_id, peer }, peer),
+        Msg2S::RemoveContact { peer } => (ContactOp::Remove { user_id, peer }, peer),
+        Msg2S::ContactsOnly { on } => (ContactOp::ContactsOnly { user_id, on }, user_id),
+        _ => unreachable!(),
+    };
+    let is_request = matches!(op, ContactOp::Request { .. });
+    // 被对方屏蔽了的话请求直接丢掉，和屏蔽消息一样不让发送者知道
+    if is_request && shared.blocks.read().unwrap().is_blocked(peer, user_id) {
+        respond(shared, user_id, addr, Msg2C::Ok);
+        return;
+    }
+
+    let mut notify = vec![];
+    let reply = {
+        let mut contacts = shared.contacts.write().unwrap();
+        match contacts.update(op) {
+            Ok(true) => {
+                if contacts.is_contact(user_id, peer) {
+                    // 接受了请求，或者双方互相请求了
+                    notify.push((peer, Msg2C::ContactAccepted { peer: user_id }));
+                    notify.push((user_id, Msg2C::ContactAccepted { peer }));
+                } else if is_request {
+                    notify.push((peer, Msg2C::ContactRequest { from: user_id }));
+                }
+                Msg2C::Ok
+            }
+            // 重复请求或者设置没有变化
+            Ok(false) if is_request || peer == user_id => Msg2C::Ok,
+            // 没有对应的请求或者联系人
+            Ok(false) => Msg2C::Err {
+                code: ErrCode::NotFound,
+            },
+            Err(e) => {
+                eprintln!("Failed to save contacts: {}", e);
+                Msg2C::Err {
+                    code: ErrCode::Unknown,
+                }
+            }
+        }
+    };
+    respond(shared, user_id, addr, reply);
+    for (peer, frame) in notify {
+        send(shared, peer, frame);
+    }
+}
+
+fn react(shared: &Shared, user_id: u64, addr: SocketAddr, msg_id: u64, emoji: &str, add: bool) {
+    // 发完再放开 reaction_dict, 保证每个人收到的汇总是按顺序的
+    let mut rd = shared.reaction_dict.lock(msg_id);
+    match rd.get_mut(msg_id) {
+        Some(reactions) if reactions.is_participant(user_id) => {
+            let changed = if add {
+                match reactions.add(user_id, emoji) {
+                    Ok(changed) => changed,
+                    Err(code) => {
+                        respond(shared, user_id, addr, Msg2C::Err { code });
+                        return;
+                    }
+                }
+            } else {
+                reactions.remove(user_id, emoji)
+            };
+            if changed {
+                // 发送者和其他参与者（包括自己）都收到最新的汇总
+                let frame = Msg2C::Reactions {
+                    msg_id,
+                    reactions: reactions.counts(),
+                };
+                for &p in reactions.participants() {
+                    send(shared, p, frame.clone());
+                }
+            }
+        }
+        _ => respond(
+            shared,
+            user_id,
+            addr,
+            Msg2C::Err {
+                code: ErrCode::NotFound,
+            },
+        ),
+    }
+}
+
+// 在线状态以 state 为准，拿着 blocks 的读锁查 state 的分片，其他地方不会反过来嵌套
+// 屏蔽了 viewer 的用户总是显示为离线
+fn directory(
+    shared: &Shared,
+    viewer: u64,
+    after_user_id: u64,
+    online_only: bool,
+    limit: usize,
+) -> (Vec<UserInfo>, bool) {
+    // 每次只拿着 users 的锁取出一批 id, 放开之后再查屏蔽，最后查名字，几把锁不同时拿
+    // 只看在线的时候一批可能不够一页，接着取下一批
+    let mut page: Vec<(u64, bool)> = vec![];
+    let mut after = after_user_id;
+    loop {
+        let batch: Vec<u64> = shared
+            .users
+            .lock()
+            .unwrap()
+            .range(after.saturating_add(1)..)
+            .take(limit + 1)
+            .copied()
+            .collect();
+        let Some(&last) = batch.last() else {
+            break;
+        };
+        after = last;
+        let exhausted = batch.len() <= limit;
+        let blocks = shared.blocks.read().unwrap();
+        page.extend(
+            batch
+                .into_iter()
+                .map(|user_id| {
+                    let online =
+                        shared.state.is_online(user_id) && !blocks.is_blocked(user_id, viewer);
+                    (user_id, online)
+                })
+                .filter(|&(_, online)| !online_only || online),
+        );
+        drop(blocks);
+        if page.len() > limit || exhausted {
+            break;
+        }
+    }
+    let has_more = page.len() > limit;
+    page.truncate(limit);
+    let profiles = shared.profiles.lock().unwrap();
+    let page = page
+        .into_iter()
+        .map(|(user_id, online)| UserInfo {
+            user_id,
+            name: profiles.name(user_id).to_string(),
+            online,
+        })
+        .collect();
+    (page, has_more)
+}
+
+fn push_offline(state: &State, user_id: u64, msg: Msg2C) {
+    // 只存之后 pull 时还有意义的消息和事件，其他的 (请求的回复、上下线通知、Page 等) 过时了就丢掉
+    // 否则会在 Page 里重放，客户端又处理一遍
+    if matches!(
+        msg,
+        Msg2C::Msg { .. }
+            | Msg2C::Update { .. }
+            | Msg2C::Reactions { .. }
+            | Msg2C::Mention { .. }
+            | Msg2C::ContactRequest { .. }
+            | Msg2C::ContactAccepted { .. }
+    ) {
+        if let Err(e) = state.offline(user_id).push(user_id, msg) {
+            eprintln!("Failed to store offline msg: {}", e);
+        }
+    }
+}
+
+// 每个连接一个，按顺序把 outbox 里的消息写出去，outbox 空了才 flush, 所有 Sender 都 drop 之后关掉 writer
+// 写失败或者超时 (客户端不读了) 就通知 recv_loop 断开，之后收到的消息都存到离线消息里
+// 连接已经断了 (evict), 或者关闭服务端时等太久了，也不再写，正在写的和剩下的都存到离线消息里
+// 接收者为 0 的是只发给这个连接的 Ok, Session 和 Quit, 写不出去就丢掉
+async fn write_loop(
+    mut writer: Writer,
+    mut rx: mpsc::Receiver<(u64, Msg2C)>,
+    state: State,
+    closed: Arc<Notify>,
+    evict: Arc<Notify>,
+    mut shutdown: watch::Receiver<Phase>,
+    _drained: mpsc::Sender<()>,
+) {
+    let mut broken = false;
+    loop {
+        let item = if broken {
+            rx.recv().await
+        } else {
+            tokio::select! {
+                item = rx.recv() => item,
+                _ = evicted(&evict, &mut shutdown) => {
+                    broken = true;
+                    continue;
+                }
+            }
+        };
+        let Some((user_id, msg)) = item else {
+            break;
+        };
+        if msg.is_expired(get_current_timestamp()) {
+            continue; // 阅后即焚，过期了就直接丢掉
+        }
+        if !broken {
+            let flush = rx.is_empty();
+            let write = async {
+                writer.write_all(&msg.to_bytes()).await?;
+                if flush {
+                    writer.flush().await?;
+                }
+                std::io::Result::Ok(())
+            };
+            tokio::select! {
+                res = tokio::time::timeout(WRITE_TIMEOUT, write) => match res {
+                    Ok(Ok(())) => continue,
+                    _ => closed.notify_one(),
+                },
+                _ = evicted(&evict, &mut shutdown) => (),
+            }
+            broken = true;
+        }
+        if user_id != 0 {
+            push_offline(&state, user_id, msg);
+        }
+    }
+    if !broken {
+        let _ = writer.flush().await;
+        let _ = writer.shutdown().await;
+    }
+}
+
+async fn expire_loop(state: State, sessions: Arc<Mutex<Sessions>>, rate_limits: RateLimits) {
+    // 还没写出去的过期消息在 write_loop 里丢弃，这里只需要清理离线消息和过期的会话
+    // 还有按用户限流的桶，已经补满的和新建的一样，删掉，否则来过的用户都一直占着
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        let now = get_current_timestamp();
+        state.expire(now);
+        sessions.lock().unwrap().expire(now);
+        let now = Instant::now();
+        rate_limits.for_each(|dict| dict.retain(|_, limiter| !limiter.is_idle(now)));
+    }
+}
@@ -0,0 +1,116 @@
+use crate::error::Result;
+use crate::msg::{skip, FrameMsg};
+use crate::oplog::{Apply, OpLog};
+use bytes::Buf;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Cursor};
+use std::path::PathBuf;
+
+// 屏蔽和取消屏蔽的操作记录，追加写到文件里，启动时按顺序重放
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockOp {
+    pub user_id: u64,
+    pub peer: u64,
+    pub blocked: bool,
+}
+
+impl FrameMsg for BlockOp {
+    fn check(src: &mut Cursor<&[u8]>) -> Result<()> {
+        skip(src, 17)
+    }
+
+    fn parse(src: &mut Cursor<&[u8]>) -> Self {
+        Self {
+            user_id: src.get_u64(),
+            peer: src.get_u64(),
+            blocked: src.get_u8() != 0,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = vec![];
+        res.extend(self.user_id.to_be_bytes());
+        res.extend(self.peer.to_be_bytes());
+        res.push(self.blocked as u8);
+        res
+    }
+}
+
+// 每个用户屏蔽了哪些人，被屏蔽的人发来的消息和看到的上线状态都由服务端过滤
+pub struct BlockList {
+    log: OpLog<BlockOp, HashMap<u64, BTreeSet<u64>>>,
+}
+
+impl Apply<BlockOp> for HashMap<u64, BTreeSet<u64>> {
+    fn apply(&mut self, op: &BlockOp) -> bool {
+        if op.blocked {
+            self.entry(op.user_id).or_default().insert(op.peer)
+        } else if let Some(peers) = self.get_mut(&op.user_id) {
+            let removed = peers.remove(&op.peer);
+            if peers.is_empty() {
+                self.remove(&op.user_id);
+            }
+            removed
+        } else {
+            false
+        }
+    }
+}
+
+impl BlockList {
+    pub fn open(path: Option<PathBuf>) -> io::Result<Self> {
+        Ok(Self {
+            log: OpLog::open(path)?,
+        })
+    }
+
+    // 返回状态是否有变化，没变化的不写文件
+    pub fn set(&mut self, user_id: u64, peer: u64, blocked: bool) -> io::Result<bool> {
+        self.log.push(BlockOp {
+            user_id,
+            peer,
+            blocked,
+        })
+    }
+
+    // user_id 是否屏蔽了 peer
+    pub fn is_blocked(&self, user_id: u64, peer: u64) -> bool {
+        self.log
+            .state()
+            .get(&user_id)
+            .is_some_and(|peers| peers.contains(&peer))
+    }
+
+    pub fn list(&self, user_id: u64) -> Vec<u64> {
+        self.log
+            .state()
+            .get(&user_id)
+            .map_or(vec![], |peers| peers.iter().copied().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_block_list() {
+        let dir = TempDir::new();
+        let path = dir.file("blocks");
+
+        let mut blocks = BlockList::open(Some(path.clone())).unwrap();
+        assert!(blocks.set(1, 3, true).unwrap());
+        assert!(blocks.set(1, 2, true).unwrap());
+        assert!(!blocks.set(1, 2, true).unwrap()); // 重复屏蔽
+        assert!(blocks.set(1, 3, false).unwrap());
+        assert!(!blocks.set(2, 1, false).unwrap());
+        assert!(blocks.is_blocked(1, 2));
+        assert!(!blocks.is_blocked(2, 1)); // 是单向的
+        drop(blocks); // 模拟重启
+
+        let blocks = BlockList::open(Some(path.clone())).unwrap();
+        assert_eq!(blocks.list(1), vec![2]);
+        assert_eq!(blocks.list(2), Vec::<u64>::new());
+    }
+}
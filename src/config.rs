@@ -0,0 +1,152 @@
+use std::env;
+use std::path::PathBuf;
+
+pub const SESSION_TTL_MAX: u64 = 365 * 24 * 3600; // 再长就和不过期差不多了，也防止转成 i64 加上当前时间溢出
+
+// 同一个用户在别处已经登录时怎么处理
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoginPolicy {
+    KickOld,   // kick, 把之前的连接踢下线
+    RejectNew, // reject, 拒绝新的登录
+    Multiple,  // multi, 允许多个设备同时在线，消息每个设备都会收到
+}
+
+impl LoginPolicy {
+    fn parse(s: &str) -> Option<Self> {
+        match s.trim() {
+            "kick" => Some(Self::KickOld),
+            "reject" => Some(Self::RejectNew),
+            "multi" => Some(Self::Multiple),
+            _ => None,
+        }
+    }
+}
+
+// 服务端配置，都从环境变量读取，没有设置时使用默认值
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub addr: String,              // MY_CHAT_ADDR
+    pub admins: Vec<u64>,          // MY_CHAT_ADMINS, 逗号分隔的 user_id
+    pub data_dir: Option<PathBuf>, // MY_CHAT_DATA_DIR, 设置了才会持久化
+    pub time_ordered_ids: bool,    // MY_CHAT_TIME_IDS, 消息 id 是否按时间有序
+    pub session_ttl: u64,          // MY_CHAT_SESSION_TTL, 会话 token 的有效期（秒）
+    // 限流，每秒最多多少帧、多少字节，0 表示不限制
+    pub conn_rate_msgs: u64,  // MY_CHAT_CONN_RATE_MSGS, 每个连接
+    pub conn_rate_bytes: u64, // MY_CHAT_CONN_RATE_BYTES
+    pub user_rate_msgs: u64,  // MY_CHAT_USER_RATE_MSGS, 每个用户的所有连接加起来
+    pub user_rate_bytes: u64, // MY_CHAT_USER_RATE_BYTES
+    // 同时最多多少个连接，0 表示不限制
+    pub max_conns: u64,            // MY_CHAT_MAX_CONNS, 总数，满了之后暂停 accept
+    pub max_conns_per_ip: u64,     // MY_CHAT_MAX_CONNS_PER_IP
+    pub max_conns_per_user: u64,   // MY_CHAT_MAX_CONNS_PER_USER, 登录后的连接
+    pub idle_timeout: u64,         // MY_CHAT_IDLE_TIMEOUT, 多少秒没收到任何帧就断开，0 表示不限制
+    pub login_policy: LoginPolicy, // MY_CHAT_LOGIN_POLICY
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:8080".to_string(),
+            admins: vec![],
+            data_dir: None,
+            time_ordered_ids: false,
+            session_ttl: 7 * 24 * 3600,
+            conn_rate_msgs: 20,
+            conn_rate_bytes: 64 * 1024,
+            user_rate_msgs: 40,
+            user_rate_bytes: 128 * 1024,
+            max_conns: 10_000,
+            max_conns_per_ip: 32,
+            max_conns_per_user: 4,
+            idle_timeout: 90,
+            login_policy: LoginPolicy::KickOld,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Ok(addr) = env::var("MY_CHAT_ADDR") {
+            config.addr = addr;
+        }
+        if let Ok(admins) = env::var("MY_CHAT_ADMINS") {
+            config.admins = parse_ids(&admins);
+        }
+        if let Ok(dir) = env::var("MY_CHAT_DATA_DIR") {
+            config.data_dir = Some(PathBuf::from(dir));
+        }
+        if let Ok(flag) = env::var("MY_CHAT_TIME_IDS") {
+            config.time_ordered_ids = parse_bool(&flag);
+        }
+        if let Some(policy) = env::var("MY_CHAT_LOGIN_POLICY")
+            .ok()
+            .and_then(|s| LoginPolicy::parse(&s))
+        {
+            config.login_policy = policy;
+        }
+        for (name, value) in [
+            ("MY_CHAT_SESSION_TTL", &mut config.session_ttl),
+            ("MY_CHAT_CONN_RATE_MSGS", &mut config.conn_rate_msgs),
+            ("MY_CHAT_CONN_RATE_BYTES", &mut config.conn_rate_bytes),
+            ("MY_CHAT_USER_RATE_MSGS", &mut config.user_rate_msgs),
+            ("MY_CHAT_USER_RATE_BYTES", &mut config.user_rate_bytes),
+            ("MY_CHAT_MAX_CONNS", &mut config.max_conns),
+            ("MY_CHAT_MAX_CONNS_PER_IP", &mut config.max_conns_per_ip),
+            ("MY_CHAT_MAX_CONNS_PER_USER", &mut config.max_conns_per_user),
+            ("MY_CHAT_IDLE_TIMEOUT", &mut config.idle_timeout),
+        ] {
+            if let Some(x) = env::var(name).ok().and_then(|s| s.trim().parse().ok()) {
+                *value = x;
+            }
+        }
+        config.session_ttl = config.session_ttl.min(SESSION_TTL_MAX);
+        config
+    }
+
+    // 持久化文件的路径，没有配置 data_dir 时返回 None
+    pub fn data_file(&self, name: &str) -> Option<PathBuf> {
+        self.data_dir.as_ref().map(|dir| dir.join(name))
+    }
+
+    pub fn is_admin(&self, user_id: u64) -> bool {
+        self.admins.contains(&user_id)
+    }
+}
+
+fn parse_bool(s: &str) -> bool {
+    matches!(s.trim(), "1" | "true" | "yes" | "on")
+}
+
+fn parse_ids(s: &str) -> Vec<u64> {
+    s.split(',')
+        .filter_map(|x| x.trim().parse::<u64>().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_ids() {
+        assert_eq!(parse_ids("1, 2,3"), vec![1, 2, 3]);
+        assert_eq!(parse_ids("1,,x,4"), vec![1, 4]);
+        assert_eq!(parse_ids(""), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_parse_login_policy() {
+        assert_eq!(LoginPolicy::parse("kick"), Some(LoginPolicy::KickOld));
+        assert_eq!(LoginPolicy::parse(" multi"), Some(LoginPolicy::Multiple));
+        assert_eq!(LoginPolicy::parse("both"), None);
+    }
+
+    #[test]
+    fn test_parse_bool() {
+        assert!(parse_bool("1"));
+        assert!(parse_bool(" true"));
+        assert!(!parse_bool("0"));
+        assert!(!parse_bool(""));
+    }
+}
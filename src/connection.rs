@@ -13,6 +13,7 @@ pub struct Connection<T: FrameMsg> {
     stream: BufReader<OwnedReadHalf>,
     buffer: BytesMut,
     cursor: usize,
+    frame_len: usize,       // 最近一次读到的帧的字节数，用于限流
     marker: PhantomData<T>, // another choice is using generic method
 }
 
@@ -23,10 +24,15 @@ impl<T: FrameMsg> Connection<T> {
             stream,
             buffer: BytesMut::with_capacity(4096),
             cursor: 0,
+            frame_len: 0,
             marker: PhantomData,
         }
     }
 
+    pub fn frame_len(&self) -> usize {
+        self.frame_len
+    }
+
     pub fn clear(&mut self) {
         self.buffer.clear(); // release used bytes
         self.cursor = 0;
@@ -82,7 +88,8 @@ impl<T: FrameMsg> Connection<T> {
             Ok(_) => {
                 buf.set_position(0);
                 let msg = T::parse(&mut buf);
-                self.cursor += buf.position() as usize;
+                self.frame_len = buf.position() as usize;
+                self.cursor += self.frame_len;
                 if self.cursor == self.buffer.len() {
                     self.clear();
                 }
@@ -0,0 +1,287 @@
+use crate::error::{Error, Result};
+use crate::msg::{get_u8, skip, FrameMsg};
+use crate::oplog::{Apply, OpLog};
+use bytes::Buf;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{self, Cursor};
+use std::path::PathBuf;
+
+// 联系人相关的操作记录，追加写到文件里，启动时按顺序重放
+#[derive(Debug, Clone, PartialEq)]
+pub enum ContactOp {
+    Request { from: u64, to: u64 },          // b"r"
+    Accept { user_id: u64, peer: u64 },      // b"a", user_id 接受 peer 的请求
+    Decline { user_id: u64, peer: u64 },     // b"d"
+    Remove { user_id: u64, peer: u64 },      // b"x", 双方都不再是联系人
+    ContactsOnly { user_id: u64, on: bool }, // b"o"
+}
+
+impl FrameMsg for ContactOp {
+    fn check(src: &mut Cursor<&[u8]>) -> Result<()> {
+        match get_u8(src)? {
+            b'r' | b'a' | b'd' | b'x' => skip(src, 16),
+            b'o' => skip(src, 9),
+            b => Err(Error::Invalid(b)),
+        }
+    }
+
+    fn parse(src: &mut Cursor<&[u8]>) -> Self {
+        match src.get_u8() {
+            b'r' => Self::Request {
+                from: src.get_u64(),
+                to: src.get_u64(),
+            },
+            b'a' => Self::Accept {
+                user_id: src.get_u64(),
+                peer: src.get_u64(),
+            },
+            b'd' => Self::Decline {
+                user_id: src.get_u64(),
+                peer: src.get_u64(),
+            },
+            b'x' => Self::Remove {
+                user_id: src.get_u64(),
+                peer: src.get_u64(),
+            },
+            b'o' => Self::ContactsOnly {
+                user_id: src.get_u64(),
+                on: src.get_u8() != 0,
+            },
+            _ => panic!("Please call check() first"),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = vec![];
+        match self {
+            Self::Request { from, to } => {
+                res.push(b'r');
+                res.extend(from.to_be_bytes());
+                res.extend(to.to_be_bytes());
+            }
+            Self::Accept { user_id, peer } => {
+                res.push(b'a');
+                res.extend(user_id.to_be_bytes());
+                res.extend(peer.to_be_bytes());
+            }
+            Self::Decline { user_id, peer } => {
+                res.push(b'd');
+                res.extend(user_id.to_be_bytes());
+                res.extend(peer.to_be_bytes());
+            }
+            Self::Remove { user_id, peer } => {
+                res.push(b'x');
+                res.extend(user_id.to_be_bytes());
+                res.extend(peer.to_be_bytes());
+            }
+            Self::ContactsOnly { user_id, on } => {
+                res.push(b'o');
+                res.extend(user_id.to_be_bytes());
+                res.push(*on as u8);
+            }
+        }
+        res
+    }
+}
+
+// 联系人是双向的：一方发请求，另一方接受之后双方互为联系人
+// 打开了 contacts_only 的用户只接收联系人的消息
+pub struct Contacts {
+    log: OpLog<ContactOp, State>,
+}
+
+#[derive(Default)]
+struct State {
+    contacts: HashMap<u64, BTreeSet<u64>>,
+    requests: HashMap<u64, BTreeSet<u64>>, // 收到的还没处理的请求 to -> from
+    contacts_only: HashSet<u64>,
+}
+
+impl Apply<ContactOp> for State {
+    fn apply(&mut self, op: &ContactOp) -> bool {
+        match *op {
+            ContactOp::Request { from, to } => {
+                if from == to || self.is_contact(from, to) {
+                    return false;
+                }
+                self.requests.entry(to).or_default().insert(from)
+            }
+            ContactOp::Accept { user_id, peer } => {
+                if !self.take_request(user_id, peer) {
+                    return false;
+                }
+                self.contacts.entry(user_id).or_default().insert(peer);
+                self.contacts.entry(peer).or_default().insert(user_id);
+                true
+            }
+            ContactOp::Decline { user_id, peer } => self.take_request(user_id, peer),
+            ContactOp::Remove { user_id, peer } => {
+                let removed = remove(&mut self.contacts, user_id, peer);
+                remove(&mut self.contacts, peer, user_id);
+                removed
+            }
+            ContactOp::ContactsOnly { user_id, on } => {
+                if on {
+                    self.contacts_only.insert(user_id)
+                } else {
+                    self.contacts_only.remove(&user_id)
+                }
+            }
+        }
+    }
+}
+
+impl State {
+    // user_id 是否收到了 peer 的请求，有的话删掉
+    fn take_request(&mut self, user_id: u64, peer: u64) -> bool {
+        remove(&mut self.requests, user_id, peer)
+    }
+
+    fn is_contact(&self, user_id: u64, peer: u64) -> bool {
+        self.contacts
+            .get(&user_id)
+            .is_some_and(|peers| peers.contains(&peer))
+    }
+}
+
+impl Contacts {
+    pub fn open(path: Option<PathBuf>) -> io::Result<Self> {
+        Ok(Self {
+            log: OpLog::open(path)?,
+        })
+    }
+
+    // 返回状态是否有变化，没变化的不写文件
+    // 对方已经给自己发过请求的话，Request 直接当作 Accept
+    pub fn update(&mut self, op: ContactOp) -> io::Result<bool> {
+        let op = match op {
+            ContactOp::Request { from, to } if self.has_request(from, to) => ContactOp::Accept {
+                user_id: from,
+                peer: to,
+            },
+            op => op,
+        };
+        self.log.push(op)
+    }
+
+    pub fn has_request(&self, user_id: u64, peer: u64) -> bool {
+        self.log
+            .state()
+            .requests
+            .get(&user_id)
+            .is_some_and(|peers| peers.contains(&peer))
+    }
+
+    pub fn is_contact(&self, user_id: u64, peer: u64) -> bool {
+        self.log.state().is_contact(user_id, peer)
+    }
+
+    pub fn is_contacts_only(&self, user_id: u64) -> bool {
+        self.log.state().contacts_only.contains(&user_id)
+    }
+
+    // to 是否接收 from 的消息
+    pub fn allows(&self, to: u64, from: u64) -> bool {
+        to == from || !self.is_contacts_only(to) || self.is_contact(to, from)
+    }
+
+    pub fn contacts(&self, user_id: u64) -> Vec<u64> {
+        list(&self.log.state().contacts, user_id)
+    }
+
+    pub fn requests(&self, user_id: u64) -> Vec<u64> {
+        list(&self.log.state().requests, user_id)
+    }
+}
+
+fn remove(dict: &mut HashMap<u64, BTreeSet<u64>>, user_id: u64, peer: u64) -> bool {
+    match dict.get_mut(&user_id) {
+        Some(peers) => {
+            let removed = peers.remove(&peer);
+            if peers.is_empty() {
+                dict.remove(&user_id);
+            }
+            removed
+        }
+        None => false,
+    }
+}
+
+fn list(dict: &HashMap<u64, BTreeSet<u64>>, user_id: u64) -> Vec<u64> {
+    dict.get(&user_id)
+        .map_or(vec![], |peers| peers.iter().copied().collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_contacts() {
+        let dir = TempDir::new();
+        let path = dir.file("contacts");
+
+        let mut contacts = Contacts::open(Some(path.clone())).unwrap();
+        assert!(contacts
+            .update(ContactOp::Request { from: 1, to: 2 })
+            .unwrap());
+        assert!(!contacts
+            .update(ContactOp::Request { from: 1, to: 2 })
+            .unwrap());
+        assert_eq!(contacts.requests(2), vec![1]);
+        assert!(!contacts
+            .update(ContactOp::Accept {
+                user_id: 1,
+                peer: 2
+            })
+            .unwrap()); // 只有收到请求的一方能接受
+        assert!(contacts
+            .update(ContactOp::Accept {
+                user_id: 2,
+                peer: 1
+            })
+            .unwrap());
+        assert!(contacts.is_contact(1, 2) && contacts.is_contact(2, 1));
+        assert_eq!(contacts.requests(2), Vec::<u64>::new());
+
+        contacts
+            .update(ContactOp::Request { from: 3, to: 1 })
+            .unwrap();
+        contacts
+            .update(ContactOp::Request { from: 1, to: 3 })
+            .unwrap(); // 互相请求
+        assert!(contacts.is_contact(1, 3));
+
+        contacts
+            .update(ContactOp::Request { from: 4, to: 1 })
+            .unwrap();
+        contacts
+            .update(ContactOp::Decline {
+                user_id: 1,
+                peer: 4,
+            })
+            .unwrap();
+        contacts
+            .update(ContactOp::Remove {
+                user_id: 3,
+                peer: 1,
+            })
+            .unwrap();
+        contacts
+            .update(ContactOp::ContactsOnly {
+                user_id: 1,
+                on: true,
+            })
+            .unwrap();
+        drop(contacts); // 模拟重启
+
+        let contacts = Contacts::open(Some(path.clone())).unwrap();
+        assert_eq!(contacts.contacts(1), vec![2]);
+        assert_eq!(contacts.requests(1), Vec::<u64>::new());
+        assert!(contacts.allows(1, 2));
+        assert!(!contacts.allows(1, 3));
+        assert!(contacts.allows(1, 1));
+        assert!(contacts.allows(3, 1)); // 没打开 contacts_only
+    }
+}
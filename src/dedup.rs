@@ -0,0 +1,77 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// 记住最近一段时间内的 (sender, fake_msg_id) -> real_msg_id,
+// 客户端重发同一条消息时返回原来的 real_msg_id, 而不是再投递一次
+pub struct SendDedup {
+    dict: HashMap<(u64, i64), u64>,
+    order: VecDeque<(Instant, (u64, i64))>, // 按插入时间排序，用于淘汰
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl SendDedup {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            dict: HashMap::new(),
+            order: VecDeque::new(),
+            ttl,
+            capacity,
+        }
+    }
+
+    // 返回 (real_msg_id, 是否是新消息)，新消息才会调用 alloc 分配 id
+    pub fn get_or_insert_with(
+        &mut self,
+        sender: u64,
+        fake_msg_id: i64,
+        alloc: impl FnOnce() -> u64,
+    ) -> (u64, bool) {
+        let now = Instant::now();
+        self.evict(now);
+
+        let key = (sender, fake_msg_id);
+        if let Some(&real_msg_id) = self.dict.get(&key) {
+            return (real_msg_id, false);
+        }
+        let real_msg_id = alloc();
+        self.dict.insert(key, real_msg_id);
+        self.order.push_back((now, key));
+        (real_msg_id, true)
+    }
+
+    fn evict(&mut self, now: Instant) {
+        while let Some(&(ts, key)) = self.order.front() {
+            if now.duration_since(ts) < self.ttl && self.order.len() < self.capacity {
+                break;
+            }
+            self.order.pop_front();
+            self.dict.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dedup() {
+        let mut dedup = SendDedup::new(Duration::from_secs(60), 2);
+        assert_eq!(dedup.get_or_insert_with(1, -1, || 100), (100, true));
+        assert_eq!(dedup.get_or_insert_with(1, -1, || 101), (100, false));
+        assert_eq!(dedup.get_or_insert_with(2, -1, || 102), (102, true)); // 不同的 sender
+
+        // 超过容量后最早的被淘汰
+        assert_eq!(dedup.get_or_insert_with(1, -2, || 103), (103, true));
+        assert_eq!(dedup.get_or_insert_with(1, -1, || 104), (104, true));
+    }
+
+    #[test]
+    fn test_dedup_ttl() {
+        let mut dedup = SendDedup::new(Duration::from_millis(10), 100);
+        assert_eq!(dedup.get_or_insert_with(1, -1, || 100), (100, true));
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(dedup.get_or_insert_with(1, -1, || 101), (101, true));
+    }
+}
@@ -0,0 +1,329 @@
+use crate::msg::Record;
+use crate::storage;
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+// 服务端保存的历史消息，按会话存放，会话内按 msg_id 排序
+// 配置了文件路径的话，每条消息都会追加写到文件里，启动时再读回来
+// 会话用 Arc 存，搜索时只在锁里复制出 Arc, 之后插入时有人还拿着才会复制一份 (make_mut)
+pub struct History {
+    convs: HashMap<(u64, u64), Arc<Vec<Record>>>,
+    writer: Option<Writer>,
+}
+
+// 追加写文件放到单独的线程里，push 只是把记录交给它，不用拿着 History 的锁等磁盘
+struct Writer {
+    tx: mpsc::Sender<Record>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl Writer {
+    fn spawn(path: PathBuf) -> Self {
+        let (tx, rx) = mpsc::channel::<Record>();
+        let handle = thread::spawn(move || {
+            for record in rx {
+                if let Err(e) = storage::append(&path, &record) {
+                    eprintln!("Failed to save history: {}", e);
+                }
+            }
+        });
+        Self { tx, handle }
+    }
+}
+
+// 搜索条件，sender/peer/since_ts/until_ts 为 0 表示不限制
+#[derive(Debug, Default)]
+pub struct Query {
+    pub text: String,
+    pub whole_word: bool,
+    pub sender: u64,
+    pub peer: u64,
+    pub since_ts: i64,
+    pub until_ts: i64,
+}
+
+impl Query {
+    fn matches(&self, user_id: u64, record: &Record) -> bool {
+        let peer = if record.from == user_id {
+            record.to
+        } else {
+            record.from
+        };
+        if (self.sender != 0 && record.from != self.sender)
+            || (self.peer != 0 && peer != self.peer)
+            || (self.since_ts != 0 && record.ts < self.since_ts)
+            || (self.until_ts != 0 && record.ts >= self.until_ts)
+        {
+            return false;
+        }
+
+        let msg = record.msg.to_lowercase();
+        let text = self.text.to_lowercase();
+        if self.whole_word {
+            // 查询里的每个词都要在消息里作为完整的词出现
+            let words: Vec<&str> = msg.split(|c: char| !c.is_alphanumeric()).collect();
+            text.split_whitespace().all(|w| words.contains(&w))
+        } else {
+            msg.contains(&text)
+        }
+    }
+}
+
+fn conv_key(a: u64, b: u64) -> (u64, u64) {
+    (a.min(b), a.max(b))
+}
+
+impl History {
+    pub fn open(path: Option<PathBuf>) -> io::Result<Self> {
+        let mut history = Self {
+            convs: HashMap::new(),
+            writer: None,
+        };
+        if let Some(path) = path {
+            for record in storage::load::<Record>(&path)? {
+                history.insert(record);
+            }
+            history.writer = Some(Writer::spawn(path));
+        }
+        Ok(history)
+    }
+
+    // 写文件的线程已经退出了才会返回错误，写失败只在那边打印出来
+    pub fn push(&mut self, record: Record) -> io::Result<()> {
+        if let Some(writer) = &self.writer {
+            writer
+                .tx
+                .send(record.clone())
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "history writer exited"))?;
+        }
+        self.insert(record);
+        Ok(())
+    }
+
+    // 等已经交给写线程的记录都写完，之后 push 的只存在内存里，关闭服务端时调用
+    pub fn close(&mut self) {
+        if let Some(Writer { tx, handle }) = self.writer.take() {
+            drop(tx);
+            let _ = handle.join();
+        }
+    }
+
+    fn insert(&mut self, record: Record) {
+        let conv = Arc::make_mut(
+            self.convs
+                .entry(conv_key(record.from, record.to))
+                .or_default(),
+        );
+        // 并发发送时不一定按 msg_id 顺序到达
+        let i = conv.partition_point(|r| r.msg_id < record.msg_id);
+        conv.insert(i, record);
+    }
+
+    // msg_id 小于 before_msg_id 的最近 limit 条 (0 表示从最新的开始)，以及是否还有更早的
+    pub fn page(
+        &self,
+        user_id: u64,
+        peer: u64,
+        before_msg_id: u64,
+        limit: usize,
+    ) -> (Vec<Record>, bool) {
+        match self.convs.get(&conv_key(user_id, peer)) {
+            Some(conv) => {
+                let end = if before_msg_id == 0 {
+                    conv.len()
+                } else {
+                    conv.partition_point(|r| r.msg_id < before_msg_id)
+                };
+                let start = end.saturating_sub(limit);
+                (conv[start..end].to_vec(), start > 0)
+            }
+            None => (vec![], false),
+        }
+    }
+
+    // user_id 参与的所有会话的快照，之后的 push 不会改到它
+    pub fn conversations(&self, user_id: u64) -> Conversations {
+        Conversations(
+            self.convs
+                .iter()
+                .filter(|(key, _)| key.0 == user_id || key.1 == user_id)
+                .map(|(_, conv)| conv.clone())
+                .collect(),
+        )
+    }
+}
+
+// History::conversations 取出来的会话，不用拿着 History 的锁搜索
+pub struct Conversations(Vec<Arc<Vec<Record>>>);
+
+impl Conversations {
+    // 在这些会话里搜索，按 msg_id 从新到旧返回 before_msg_id 之前的 limit 条
+    pub fn search(
+        &self,
+        user_id: u64,
+        query: &Query,
+        before_msg_id: u64,
+        limit: usize,
+    ) -> (Vec<Record>, bool) {
+        let mut res: Vec<&Record> = self
+            .0
+            .iter()
+            .flat_map(|conv| conv.iter())
+            .filter(|r| before_msg_id == 0 || r.msg_id < before_msg_id)
+            .filter(|r| query.matches(user_id, r))
+            .collect();
+        res.sort_unstable_by_key(|r| (std::cmp::Reverse(r.msg_id), r.to));
+        // 发给多个人的消息每个会话存了一条，msg_id 相同，只留一条 (to 最小的)
+        // 否则结果里有重复，按 before_msg_id 翻页时还会跳过同一个 msg_id 剩下的几条
+        res.dedup_by_key(|r| r.msg_id);
+        let has_more = res.len() > limit;
+        (res.into_iter().take(limit).cloned().collect(), has_more)
+    }
+}
+
+impl Drop for History {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn record(msg_id: u64, from: u64, to: u64) -> Record {
+        Record {
+            msg_id,
+            from,
+            to,
+            ts: 0,
+            msg: format!("msg {}", msg_id),
+        }
+    }
+
+    #[test]
+    fn test_history_page() {
+        let mut history = History::open(None).unwrap();
+        history.push(record(1, 1, 2)).unwrap();
+        history.push(record(3, 2, 1)).unwrap();
+        history.push(record(2, 1, 2)).unwrap();
+        history.push(record(4, 1, 3)).unwrap();
+
+        let (records, has_more) = history.page(1, 2, 0, 2);
+        let ids: Vec<u64> = records.iter().map(|r| r.msg_id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert!(has_more);
+
+        let (records, has_more) = history.page(2, 1, 2, 2);
+        let ids: Vec<u64> = records.iter().map(|r| r.msg_id).collect();
+        assert_eq!(ids, vec![1]);
+        assert!(!has_more);
+
+        assert_eq!(history.page(2, 3, 0, 10), (vec![], false));
+    }
+
+    #[test]
+    fn test_history_search() {
+        let mut history = History::open(None).unwrap();
+        for (msg_id, from, to, msg) in [
+            (1, 1, 2, "see https://example.com"),
+            (2, 2, 1, "Thanks for the LINK"),
+            (3, 3, 1, "linked list"),
+            (4, 2, 3, "link between others"),
+        ] {
+            history
+                .push(Record {
+                    msg_id,
+                    from,
+                    to,
+                    ts: msg_id as i64 * 100,
+                    msg: msg.to_string(),
+                })
+                .unwrap();
+        }
+        let ids = |(records, _): (Vec<Record>, bool)| -> Vec<u64> {
+            records.iter().map(|r| r.msg_id).collect()
+        };
+
+        let mut query = Query {
+            text: "link".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(history.conversations(1).search(1, &query, 0, 10)),
+            vec![3, 2]
+        ); // 看不到别人的会话
+        assert!(history.conversations(1).search(1, &query, 0, 1).1);
+        assert_eq!(
+            ids(history.conversations(1).search(1, &query, 3, 10)),
+            vec![2]
+        );
+
+        query.whole_word = true;
+        assert_eq!(
+            ids(history.conversations(1).search(1, &query, 0, 10)),
+            vec![2]
+        );
+
+        query.whole_word = false;
+        query.peer = 3;
+        assert_eq!(
+            ids(history.conversations(1).search(1, &query, 0, 10)),
+            vec![3]
+        );
+
+        query.text = "example".to_string();
+        query.peer = 0;
+        query.sender = 1;
+        query.since_ts = 100;
+        query.until_ts = 200;
+        assert_eq!(
+            ids(history.conversations(1).search(1, &query, 0, 10)),
+            vec![1]
+        );
+        query.since_ts = 101;
+        assert_eq!(
+            ids(history.conversations(1).search(1, &query, 0, 10)),
+            Vec::<u64>::new()
+        );
+
+        // 取出来之后再 push 的不在快照里
+        let convs = history.conversations(1);
+        history.push(record(5, 1, 2)).unwrap();
+        query = Query::default();
+        assert_eq!(ids(convs.search(1, &query, 0, 10)), vec![3, 2, 1]);
+        assert_eq!(
+            ids(history.conversations(1).search(1, &query, 0, 10)),
+            vec![5, 3, 2, 1]
+        );
+
+        // 同时发给 3 和 2 的消息只出现一次，has_more 也按去重之后算
+        for to in [3, 2] {
+            history.push(record(6, 1, to)).unwrap();
+        }
+        let (records, has_more) = history.conversations(1).search(1, &query, 0, 2);
+        assert_eq!(records, vec![record(6, 1, 2), record(5, 1, 2)]);
+        assert!(has_more);
+        assert_eq!(
+            ids(history.conversations(1).search(1, &query, 6, 10)),
+            vec![5, 3, 2, 1]
+        );
+    }
+
+    #[test]
+    fn test_history_persist() {
+        let dir = TempDir::new();
+        let path = dir.file("history");
+
+        let mut history = History::open(Some(path.clone())).unwrap();
+        history.push(record(1, 1, 2)).unwrap();
+        drop(history);
+
+        let history = History::open(Some(path.clone())).unwrap();
+        assert_eq!(history.page(1, 2, 0, 10).0, vec![record(1, 1, 2)]);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::storage::write_atomic;
+use chrono::Local;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+const SEQ_BITS: u32 = 16; // 时间有序模式下，同一毫秒内的序号位数
+const RESERVE: u64 = 1 << SEQ_BITS; // 每次预留多少个 id 再写一次文件
+const RESERVE_MILLIS: u64 = 60_000; // 时间有序模式下 id 跟着时间涨，每次预留这么多毫秒
+
+// 服务端全局唯一、单调递增的消息 id
+// 配置了持久化文件时，先把预留的上限写进文件再分配，
+// 这样即使进程崩溃，重启后也会从上限之后开始，不会和之前的 id 重复
+// 平时只用原子操作，只有超过上限要写文件时才加锁
+pub struct IdAllocator {
+    last: AtomicU64,     // 最近分配的 id
+    reserved: AtomicU64, // 已经写进文件的上限
+    persist: Mutex<()>,  // 写文件时加锁，同时只有一个线程写
+    time_ordered: bool,
+    path: Option<PathBuf>,
+}
+
+impl IdAllocator {
+    pub fn new(path: Option<PathBuf>, time_ordered: bool) -> io::Result<Self> {
+        let last = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(s) => s
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e),
+            },
+            None => 0,
+        };
+        Ok(Self {
+            last: AtomicU64::new(last),
+            reserved: AtomicU64::new(last),
+            persist: Mutex::new(()),
+            time_ordered,
+            path,
+        })
+    }
+
+    // 写文件失败时返回错误，不分配可能重复的 id, 调用的地方可能拿着别的锁，不能 panic
+    pub fn next(&self) -> io::Result<u64> {
+        let now_ms = Local::now().timestamp_millis() as u64;
+        let id = if self.time_ordered {
+            // 类似 snowflake: 高位是毫秒时间戳，低位是序号
+            let now = now_ms << SEQ_BITS;
+            let last = self
+                .last
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+                    Some((last + 1).max(now))
+                })
+                .unwrap();
+            (last + 1).max(now)
+        } else {
+            self.last.fetch_add(1, Ordering::SeqCst) + 1
+        };
+
+        if let Some(path) = &self.path {
+            if id > self.reserved.load(Ordering::SeqCst) {
+                let _lock = self.persist.lock().unwrap();
+                // 等锁的时候可能已经被别的线程写过了
+                if id > self.reserved.load(Ordering::SeqCst) {
+                    // 时间有序模式下只预留 RESERVE 个的话几乎每毫秒都要写一次，改为预留一段时间
+                    let reserved = if self.time_ordered {
+                        (id + RESERVE).max((now_ms + RESERVE_MILLIS) << SEQ_BITS)
+                    } else {
+                        id + RESERVE
+                    };
+                    write_atomic(path, reserved.to_string().as_bytes())?;
+                    self.reserved.store(reserved, Ordering::SeqCst);
+                }
+            }
+        }
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_seq_ids() {
+        let ids = IdAllocator::new(None, false).unwrap();
+        assert_eq!(ids.next().unwrap(), 1);
+        assert_eq!(ids.next().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_time_ordered_ids() {
+        let ids = IdAllocator::new(None, true).unwrap();
+        let now = (Local::now().timestamp_millis() as u64) << SEQ_BITS;
+        let a = ids.next().unwrap();
+        let b = ids.next().unwrap();
+        assert!(a >= now);
+        assert!(b > a);
+    }
+
+    #[test]
+    fn test_persisted_ids() {
+        let dir = TempDir::new();
+        let path = dir.file("ids");
+
+        let ids = IdAllocator::new(Some(path.clone()), false).unwrap();
+        let last = (0..10).map(|_| ids.next().unwrap()).last().unwrap();
+        drop(ids); // 模拟重启
+
+        let ids = IdAllocator::new(Some(path.clone()), false).unwrap();
+        assert!(ids.next().unwrap() > last);
+
+        // 写不了文件的话不分配
+        let ids = IdAllocator::new(Some(dir.file("missing/ids")), false).unwrap();
+        assert!(ids.next().is_err());
+        assert!(ids.next().is_err());
+    }
+
+    #[test]
+    fn test_persisted_time_ids() {
+        let dir = TempDir::new();
+        let path = dir.file("time_ids");
+
+        let ids = IdAllocator::new(Some(path.clone()), true).unwrap();
+        let first = ids.next().unwrap();
+        let reserved = fs::read_to_string(&path).unwrap();
+        let last = (0..10).map(|_| ids.next().unwrap()).last().unwrap();
+        // 一分钟之内不用再写文件
+        assert_eq!(fs::read_to_string(&path).unwrap(), reserved);
+        assert!(reserved.parse::<u64>().unwrap() >= first + (RESERVE_MILLIS << SEQ_BITS));
+        drop(ids); // 模拟重启
+
+        let ids = IdAllocator::new(Some(path.clone()), true).unwrap();
+        assert!(ids.next().unwrap() > last);
+    }
+
+    #[test]
+    fn test_concurrent_ids() {
+        let ids = IdAllocator::new(None, true).unwrap();
+        let mut all: Vec<u64> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| s.spawn(|| (0..1000).map(|_| ids.next().unwrap()).collect::<Vec<u64>>()))
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap())
+                .collect()
+        });
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 4000);
+    }
+}
@@ -1,4 +1,27 @@
+pub mod account;
+pub mod ban;
+pub mod block;
+pub mod config;
 pub mod connection;
+pub mod contact;
+pub mod dedup;
 pub mod error;
+pub mod history;
+pub mod id;
+pub mod limit;
+pub mod mention;
 pub mod msg;
+pub mod offline;
+pub mod oplog;
+pub mod profile;
+pub mod ratelimit;
+pub mod reaction;
+pub mod registry;
+pub mod session;
+pub mod shard;
+pub mod state;
+pub mod storage;
 pub mod time;
+
+#[cfg(test)]
+mod testutil;
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+// 按 key 计数的并发上限，比如每个 IP、每个用户同时有多少个连接，max 为 0 表示不限制
+pub struct ConnLimiter<K> {
+    max: usize,
+    counts: Arc<Mutex<HashMap<K, usize>>>,
+}
+
+impl<K> Clone for ConnLimiter<K> {
+    fn clone(&self) -> Self {
+        Self {
+            max: self.max,
+            counts: self.counts.clone(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> ConnLimiter<K> {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            counts: Default::default(),
+        }
+    }
+
+    // 没超过上限的话计数加一，返回的 guard 被 drop 时再减回去
+    pub fn try_acquire(&self, key: K) -> Option<ConnGuard<K>> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(key.clone()).or_default();
+        if self.max != 0 && *count >= self.max {
+            if *count == 0 {
+                counts.remove(&key);
+            }
+            return None;
+        }
+        *count += 1;
+        Some(ConnGuard {
+            key,
+            counts: self.counts.clone(),
+        })
+    }
+
+    // 不管上限都计数，用于马上就会腾出位置的情况，比如登录后会踢掉旧的连接
+    pub fn acquire(&self, key: K) -> ConnGuard<K> {
+        *self.counts.lock().unwrap().entry(key.clone()).or_default() += 1;
+        ConnGuard {
+            key,
+            counts: self.counts.clone(),
+        }
+    }
+
+    pub fn count(&self, key: &K) -> usize {
+        self.counts.lock().unwrap().get(key).copied().unwrap_or(0)
+    }
+}
+
+pub struct ConnGuard<K: Hash + Eq> {
+    key: K,
+    counts: Arc<Mutex<HashMap<K, usize>>>,
+}
+
+impl<K: Hash + Eq> Drop for ConnGuard<K> {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_conn_limiter() {
+        let limiter = ConnLimiter::new(2);
+        let a = limiter.try_acquire(1).unwrap();
+        let b = limiter.try_acquire(1).unwrap();
+        assert!(limiter.try_acquire(1).is_none());
+        let c = limiter.try_acquire(2).unwrap(); // 不同的 key 分开计数
+        assert_eq!(limiter.count(&1), 2);
+
+        drop(a);
+        assert_eq!(limiter.count(&1), 1);
+        let a = limiter.try_acquire(1).unwrap();
+        let d = limiter.acquire(1); // 满了也能计数
+        assert_eq!(limiter.count(&1), 3);
+        assert!(limiter.try_acquire(1).is_none());
+        drop((a, b, c, d));
+        assert_eq!(limiter.count(&1), 0);
+        assert!(limiter.counts.lock().unwrap().is_empty());
+
+        let unlimited = ConnLimiter::new(0);
+        let guards: Vec<_> = (0..100)
+            .map(|_| unlimited.try_acquire(1).unwrap())
+            .collect();
+        assert_eq!(unlimited.count(&1), guards.len());
+    }
+}
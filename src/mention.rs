@@ -0,0 +1,37 @@
+use regex::Regex;
+use std::sync::OnceLock;
+
+// 消息中的 @user_id，按出现顺序去重
+pub fn parse_mentions(msg: &str) -> Vec<u64> {
+    static REG: OnceLock<Regex> = OnceLock::new();
+    let reg = REG.get_or_init(|| Regex::new(r"(?:^|\s)@(\d+)\b").unwrap());
+
+    let mut res = vec![];
+    for caps in reg.captures_iter(msg) {
+        if let Ok(user_id) = caps[1].parse::<u64>() {
+            if !res.contains(&user_id) {
+                res.push(user_id);
+            }
+        }
+    }
+    res
+}
+
+pub fn is_mentioned(msg: &str, user_id: u64) -> bool {
+    parse_mentions(msg).contains(&user_id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_mentions() {
+        assert_eq!(parse_mentions("@12 @34 hi @12"), vec![12, 34]);
+        assert_eq!(parse_mentions("hi,@56\t@78"), vec![78]);
+        assert_eq!(parse_mentions("mail me: a@12.com"), Vec::<u64>::new());
+        assert_eq!(parse_mentions("@abc @"), Vec::<u64>::new());
+        assert!(is_mentioned("ping @5678", 5678));
+        assert!(!is_mentioned("ping @56789", 5678));
+    }
+}
@@ -15,7 +15,8 @@ pub enum Msg2C {
     Msg {
         msg_id: u64,
         from: u64,
-        ts: i64, // use chrono
+        ts: i64,        // use chrono
+        expire_at: i64, // 阅后即焚的过期时间，0 表示不过期
         len: u64,
         msg: String,
     },
@@ -25,28 +26,249 @@ pub enum Msg2C {
         real_msg_id: u64,
     },
 
-    Quit,         // b"q"
-    Ok,           // b"o"
-    Err,          // b"e"
+    Reactions {
+        // b"r", 某条消息当前的 reaction 汇总，而不是单次的增减
+        msg_id: u64,
+        reactions: Vec<(String, u64)>, // (emoji, count)
+    },
+
+    Mention {
+        // b"@", 静音了对方也会收到
+        msg_id: u64,
+        from: u64,
+        expire_at: i64,
+        msg: String,
+    },
+
+    Page {
+        // b"p", 一页离线消息，客户端处理完后用 last_msg_id 来 ack 和拉取下一页
+        last_msg_id: u64,
+        has_more: bool,
+        msgs: Vec<Msg2C>,
+    },
+
+    History {
+        // b"h", 和 peer 的历史消息，按 msg_id 从旧到新
+        peer: u64,
+        has_more: bool, // 是否还有更早的
+        records: Vec<Record>,
+    },
+
+    SearchResult {
+        // b"s", 按 msg_id 从新到旧
+        has_more: bool,
+        records: Vec<Record>,
+    },
+
+    Directory {
+        // b"d", 按 user_id 从小到大
+        has_more: bool,
+        users: Vec<UserInfo>,
+    },
+
+    Profile {
+        // b"n", 查询或修改资料的结果
+        profile: Profile,
+    },
+
+    Session {
+        // b"t", 登录或恢复会话成功后发给客户端，断线重连时用 Resume 带回来
+        token: String,
+        expire_at: i64,
+    },
+
+    Presence {
+        // b"P", 某个用户上线或下线了，只实时推送，不存离线
+        user_id: u64,
+        online: bool,
+    },
+
+    Blocked {
+        // b"b", 自己屏蔽的用户列表
+        users: Vec<u64>,
+    },
+
+    Contacts {
+        // b"c", 联系人、收到的还没处理的请求和是否只接收联系人的消息
+        contacts: Vec<u64>,
+        requests: Vec<u64>,
+        contacts_only: bool,
+    },
+
+    ContactRequest {
+        // b"f", 收到 from 的联系人请求
+        from: u64,
+    },
+
+    ContactAccepted {
+        // b"y", 和 peer 成为联系人了
+        peer: u64,
+    },
+
+    Announce {
+        // b"A", 管理员发的全服公告，只推送给在线用户
+        from: u64,
+        msg: String,
+    },
+
+    Stats {
+        // b"S", 服务端当前的队列大小，只有管理员能查询, msg_queue 是所有连接还没写出去的消息数
+        connected: u64,
+        users: u64,
+        msg_queue: u64,
+        offline_users: u64,
+        offline_msgs: u64,
+        banned: Vec<u64>,
+    },
+
+    Quit, // b"q"
+    Ok,   // b"o"
+    Err {
+        // b"e"
+        code: ErrCode,
+    },
     AuthRequired, // b'a'
 }
 
+impl Msg2C {
+    // 过期的阅后即焚消息，不管是还没写出去还是在离线消息里都不能再投递
+    pub fn is_expired(&self, now: i64) -> bool {
+        match self {
+            Self::Msg { expire_at, .. } | Self::Mention { expire_at, .. } => {
+                *expire_at > 0 && *expire_at <= now
+            }
+            _ => false,
+        }
+    }
+
+    // 带 token 的帧和阅后即焚的消息不能打印到日志里，离线消息的 Page 里有的话也不行
+    pub fn has_secret(&self) -> bool {
+        match self {
+            Self::Session { .. } => true,
+            Self::Msg { expire_at, .. } | Self::Mention { expire_at, .. } => *expire_at > 0,
+            Self::Page { msgs, .. } => msgs.iter().any(Self::has_secret),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrCode {
+    Unknown = 0,
+    NotFound = 1,       // 目标消息不存在
+    Forbidden = 2,      // 没有权限
+    Invalid = 3,        // 参数不合法，比如名字太长
+    AuthFailed = 4,     // user_id 不存在或者密码错误
+    Exists = 5,         // user_id 已经被注册了
+    NotContact = 6,     // 对方只接收联系人的消息
+    Banned = 7,         // user_id 被管理员封禁了
+    RateLimited = 8,    // 发得太快了，这一帧被丢弃
+    Busy = 9,           // 连接数超过上限
+    Replaced = 10,      // 同一个账号在别处登录，这个连接被顶掉了
+    AlreadyOnline = 11, // 同一个账号已经在别处登录，服务端不允许重复登录
+    ShuttingDown = 12,  // 服务端要关闭了，之后会收到 Quit
+}
+
+impl From<u8> for ErrCode {
+    fn from(src: u8) -> ErrCode {
+        match src {
+            1 => Self::NotFound,
+            2 => Self::Forbidden,
+            3 => Self::Invalid,
+            4 => Self::AuthFailed,
+            5 => Self::Exists,
+            6 => Self::NotContact,
+            7 => Self::Banned,
+            8 => Self::RateLimited,
+            9 => Self::Busy,
+            10 => Self::Replaced,
+            11 => Self::AlreadyOnline,
+            12 => Self::ShuttingDown,
+            _ => Self::Unknown,
+        }
+    }
+}
+
 impl FrameMsg for Msg2C {
     fn check(src: &mut Cursor<&[u8]>) -> Result<()> {
         match get_u8(src)? {
             b'<' => {
                 let start = src.position() as usize;
                 let end = src.get_ref().len();
-                if end - start < 32 {
+                if end - start < 40 {
                     Err(Error::Incomplete)
                 } else {
-                    skip(src, 24)?;
+                    skip(src, 32)?;
                     let len = src.get_u64();
                     skip(src, len as usize)
                 }
             }
             b'u' => skip(src, 16),
-            b'q' | b'o' | b'e' | b'a' => Ok(()),
+            b'r' => {
+                skip(src, 8)?;
+                for _ in 0..get_u64(src)? {
+                    skip_str(src)?;
+                    skip(src, 8)?;
+                }
+                Ok(())
+            }
+            b'@' => {
+                skip(src, 24)?;
+                skip_str(src)
+            }
+            b'p' => {
+                skip(src, 9)?;
+                for _ in 0..get_u64(src)? {
+                    let len = get_u64(src)? as usize;
+                    if src.remaining() < len {
+                        return Err(Error::Incomplete);
+                    }
+                    // 外层已经完整了，内层再不完整就是格式错误
+                    let start = src.position() as usize;
+                    let mut inner = Cursor::new(&src.get_ref()[start..start + len]);
+                    match Self::check(&mut inner) {
+                        Ok(_) if inner.position() as usize == len => (),
+                        _ => return Err(Error::Invalid(b'p')),
+                    }
+                    src.advance(len);
+                }
+                Ok(())
+            }
+            b'h' => {
+                skip(src, 9)?;
+                skip_list::<Record>(src)
+            }
+            b's' => {
+                skip(src, 1)?;
+                skip_list::<Record>(src)
+            }
+            b'd' => {
+                skip(src, 1)?;
+                skip_list::<UserInfo>(src)
+            }
+            b'n' => Profile::check(src),
+            b't' => {
+                skip_str(src)?;
+                skip(src, 8)
+            }
+            b'P' => skip(src, 9),
+            b'b' => skip_u64s(src, b'b'),
+            b'c' => {
+                skip_u64s(src, b'c')?;
+                skip_u64s(src, b'c')?;
+                skip(src, 1)
+            }
+            b'f' | b'y' => skip(src, 8),
+            b'A' => {
+                skip(src, 8)?;
+                skip_str(src)
+            }
+            b'S' => {
+                skip(src, 40)?;
+                skip_u64s(src, b'S')
+            }
+            b'e' => skip(src, 1),
+            b'q' | b'o' | b'a' => Ok(()),
             b => Err(Error::Invalid(b)),
         }
     }
@@ -57,6 +279,7 @@ impl FrameMsg for Msg2C {
                 let msg_id = src.get_u64();
                 let from = src.get_u64();
                 let ts = src.get_i64();
+                let expire_at = src.get_i64();
                 let len = src.get_u64();
                 let mut bytes = vec![0; len as usize];
                 // Vec::with_capacity(len as usize); // get 0 bytes
@@ -67,6 +290,7 @@ impl FrameMsg for Msg2C {
                     msg_id,
                     from,
                     ts,
+                    expire_at,
                     len,
                     msg,
                 }
@@ -75,9 +299,89 @@ impl FrameMsg for Msg2C {
                 fake_msg_id: src.get_i64(),
                 real_msg_id: src.get_u64(),
             },
+            b'r' => {
+                let msg_id = src.get_u64();
+                let n = src.get_u64();
+                let reactions = (0..n).map(|_| (read_str(src), src.get_u64())).collect();
+                Self::Reactions { msg_id, reactions }
+            }
+            b'@' => Self::Mention {
+                msg_id: src.get_u64(),
+                from: src.get_u64(),
+                expire_at: src.get_i64(),
+                msg: read_str(src),
+            },
+            b'p' => {
+                let last_msg_id = src.get_u64();
+                let has_more = src.get_u8() != 0;
+                let n = src.get_u64();
+                let msgs = (0..n)
+                    .map(|_| {
+                        src.get_u64(); // len, 只在 check 时用到
+                        Self::parse(src)
+                    })
+                    .collect();
+                Self::Page {
+                    last_msg_id,
+                    has_more,
+                    msgs,
+                }
+            }
+            b'h' => Self::History {
+                peer: src.get_u64(),
+                has_more: src.get_u8() != 0,
+                records: read_list(src),
+            },
+            b's' => Self::SearchResult {
+                has_more: src.get_u8() != 0,
+                records: read_list(src),
+            },
+            b'd' => Self::Directory {
+                has_more: src.get_u8() != 0,
+                users: read_list(src),
+            },
+            b'n' => Self::Profile {
+                profile: Profile::parse(src),
+            },
+            b't' => Self::Session {
+                token: read_str(src),
+                expire_at: src.get_i64(),
+            },
+            b'P' => Self::Presence {
+                user_id: src.get_u64(),
+                online: src.get_u8() != 0,
+            },
+            b'b' => Self::Blocked {
+                users: read_u64s(src),
+            },
+            b'c' => Self::Contacts {
+                contacts: read_u64s(src),
+                requests: read_u64s(src),
+                contacts_only: src.get_u8() != 0,
+            },
+            b'f' => Self::ContactRequest {
+                from: src.get_u64(),
+            },
+            b'y' => Self::ContactAccepted {
+                peer: src.get_u64(),
+            },
+            b'A' => Self::Announce {
+                from: src.get_u64(),
+                msg: read_str(src),
+            },
+            b'S' => Self::Stats {
+                connected: src.get_u64(),
+                users: src.get_u64(),
+                msg_queue: src.get_u64(),
+                offline_users: src.get_u64(),
+                offline_msgs: src.get_u64(),
+                banned: read_u64s(src),
+            },
             b'q' => Self::Quit,
             b'o' => Self::Ok,
-            b'e' => Self::Err,
+            b'e' => Self::Err {
+                code: src.get_u8().into(),
+            },
             b'a' => Self::AuthRequired,
             _ => panic!("Please call check() first"),
         }
@@ -90,6 +394,7 @@ impl FrameMsg for Msg2C {
                 msg_id,
                 from,
                 ts,
+                expire_at,
                 len,
                 msg,
             } => {
@@ -97,6 +402,7 @@ impl FrameMsg for Msg2C {
                 res.extend(msg_id.to_be_bytes());
                 res.extend(from.to_be_bytes());
                 res.extend(ts.to_be_bytes());
+                res.extend(expire_at.to_be_bytes());
                 res.extend(len.to_be_bytes());
                 res.extend(msg.bytes());
             }
@@ -108,9 +414,125 @@ impl FrameMsg for Msg2C {
                 res.extend(fake_msg_id.to_be_bytes());
                 res.extend(real_msg_id.to_be_bytes());
             }
+            Self::Reactions { msg_id, reactions } => {
+                res.push(b'r');
+                res.extend(msg_id.to_be_bytes());
+                res.extend((reactions.len() as u64).to_be_bytes());
+                for (emoji, count) in reactions {
+                    put_str(&mut res, emoji);
+                    res.extend(count.to_be_bytes());
+                }
+            }
+            Self::Mention {
+                msg_id,
+                from,
+                expire_at,
+                msg,
+            } => {
+                res.push(b'@');
+                res.extend(msg_id.to_be_bytes());
+                res.extend(from.to_be_bytes());
+                res.extend(expire_at.to_be_bytes());
+                put_str(&mut res, msg);
+            }
+            Self::Page {
+                last_msg_id,
+                has_more,
+                msgs,
+            } => {
+                res.push(b'p');
+                res.extend(last_msg_id.to_be_bytes());
+                res.push(*has_more as u8);
+                res.extend((msgs.len() as u64).to_be_bytes());
+                for msg in msgs {
+                    let bytes = msg.to_bytes();
+                    res.extend((bytes.len() as u64).to_be_bytes());
+                    res.extend(bytes);
+                }
+            }
+            Self::History {
+                peer,
+                has_more,
+                records,
+            } => {
+                res.push(b'h');
+                res.extend(peer.to_be_bytes());
+                res.push(*has_more as u8);
+                put_list(&mut res, records);
+            }
+            Self::SearchResult { has_more, records } => {
+                res.push(b's');
+                res.push(*has_more as u8);
+                put_list(&mut res, records);
+            }
+            Self::Directory { has_more, users } => {
+                res.push(b'd');
+                res.push(*has_more as u8);
+                put_list(&mut res, users);
+            }
+            Self::Profile { profile } => {
+                res.push(b'n');
+                res.extend(profile.to_bytes());
+            }
+            Self::Session { token, expire_at } => {
+                res.push(b't');
+                put_str(&mut res, token);
+                res.extend(expire_at.to_be_bytes());
+            }
+            Self::Presence { user_id, online } => {
+                res.push(b'P');
+                res.extend(user_id.to_be_bytes());
+                res.push(*online as u8);
+            }
+            Self::Blocked { users } => {
+                res.push(b'b');
+                put_u64s(&mut res, users);
+            }
+            Self::Contacts {
+                contacts,
+                requests,
+                contacts_only,
+            } => {
+                res.push(b'c');
+                put_u64s(&mut res, contacts);
+                put_u64s(&mut res, requests);
+                res.push(*contacts_only as u8);
+            }
+            Self::ContactRequest { from } => {
+                res.push(b'f');
+                res.extend(from.to_be_bytes());
+            }
+            Self::ContactAccepted { peer } => {
+                res.push(b'y');
+                res.extend(peer.to_be_bytes());
+            }
+            Self::Announce { from, msg } => {
+                res.push(b'A');
+                res.extend(from.to_be_bytes());
+                put_str(&mut res, msg);
+            }
+            Self::Stats {
+                connected,
+                users,
+                msg_queue,
+                offline_users,
+                offline_msgs,
+                banned,
+            } => {
+                res.push(b'S');
+                res.extend(connected.to_be_bytes());
+                res.extend(users.to_be_bytes());
+                res.extend(msg_queue.to_be_bytes());
+                res.extend(offline_users.to_be_bytes());
+                res.extend(offline_msgs.to_be_bytes());
+                put_u64s(&mut res, banned);
+            }
             Self::Quit => res.push(b'q'),
             Self::Ok => res.push(b'o'),
-            Self::Err => res.push(b'e'),
+            Self::Err { code } => {
+                res.push(b'e');
+                res.push(*code as u8);
+            }
             Self::AuthRequired => res.push(b'a'),
         }
         res
@@ -122,6 +544,7 @@ pub enum Msg2S {
     Msg {
         fake_msg_id: i64, // use negative
         to: u64,
+        ttl: u64, // 秒，0 表示不过期
         len: u64,
         msg: String,
     }, // need to send to another user
@@ -129,30 +552,231 @@ pub enum Msg2S {
     Login {
         // b"l"
         user_id: u64,
-        // len: usize,
-        // password: String,
+        password: String,
+    },
+
+    Register {
+        // b"R", 成功返回 Ok, 之后还需要再登录
+        user_id: u64,
+        password: String,
+    },
+
+    Resume {
+        // b"T", 用之前拿到的 token 代替 Login, 成功返回 Ok 和新的 Session
+        token: String,
+    },
+
+    MultiMsg {
+        // b"*", 一条消息发给多个人，共用一个 real_msg_id
+        fake_msg_id: i64,
+        to: Vec<u64>,
+        ttl: u64,
+        msg: String,
+    },
+
+    Broadcast {
+        // b"!", 发给所有用户，需要管理员权限
+        fake_msg_id: i64,
+        ttl: u64,
+        msg: String,
+    },
+
+    React {
+        // b"+"
+        msg_id: u64,
+        emoji: String,
+    },
+
+    Unreact {
+        // b"-"
+        msg_id: u64,
+        emoji: String,
+    },
+
+    Mute {
+        // b"m", 静音和 peer 的会话，消息只存离线不实时推送
+        peer: u64,
+    },
+
+    Unmute {
+        // b"M"
+        peer: u64,
+    },
+
+    Block {
+        // b"b", 屏蔽 peer: 不再收到他的消息，他也看不到自己的上线状态
+        peer: u64,
+    },
+
+    Unblock {
+        // b"B"
+        peer: u64,
+    },
+
+    ListBlocked, // b"L", 服务端回复 Blocked
+
+    AddContact {
+        // b"c", 给 peer 发联系人请求，对方已经请求过自己的话直接成为联系人
+        peer: u64,
+    },
+
+    AcceptContact {
+        // b"y"
+        peer: u64,
+    },
+
+    DeclineContact {
+        // b"x"
+        peer: u64,
+    },
+
+    RemoveContact {
+        // b"C", 双方都不再是联系人
+        peer: u64,
+    },
+
+    ListContacts, // b"i", 服务端回复 Contacts
+
+    ContactsOnly {
+        // b"o", 打开后只接收联系人的消息
+        on: bool,
+    },
+
+    History {
+        // b"h", 和 peer 的会话中 msg_id 小于 before_msg_id 的最近 limit 条消息，0 表示从最新的开始
+        peer: u64,
+        before_msg_id: u64,
+        limit: u64,
+    },
+
+    Search {
+        // b"s", 在自己的会话里搜索，sender/peer/since_ts/until_ts 为 0 表示不限制
+        query: String,
+        whole_word: bool, // true 按词匹配，false 按子串匹配
+        sender: u64,
+        peer: u64,
+        since_ts: i64,
+        until_ts: i64,
+        before_msg_id: u64, // 分页，0 表示从最新的开始
+        limit: u64,
+    },
+
+    Directory {
+        // b"d", user_id 大于 after_user_id 的最多 limit 个用户
+        after_user_id: u64,
+        online_only: bool,
+        limit: u64,
+    },
+
+    SetProfile {
+        // b"n", 整体替换自己的资料，空字符串表示清除
+        name: String,
+        status: String,
+    },
+
+    GetProfile {
+        // b"g"
+        user_id: u64,
+    },
+
+    Pull {
+        // b"p", 拉取 cursor 在 since_msg_id 之后的最多 limit 条离线消息
+        since_msg_id: u64,
+        limit: u64,
+    },
+
+    Ack {
+        // b"k", 确认 cursor 不大于 msg_id 的离线消息都已收到，服务端可以删除了
+        msg_id: u64,
     },
 
-    Pull, // b"p" // pull
+    Logout, // b"q", 服务端回复 Quit 后关闭连接，token 也会失效
+
+    // 以下只有管理员能用，其他用户会收到 Err Forbidden
+    Kick {
+        // b"K", 关闭 user_id 的连接并让他的 token 失效
+        user_id: u64,
+    },
+
+    Ban {
+        // b"X", 踢下线并且不能再登录
+        user_id: u64,
+    },
+
+    Unban {
+        // b"U"
+        user_id: u64,
+    },
+
+    Announce {
+        // b"A", 全服公告
+        msg: String,
+    },
+
+    Stats, // b"S", 服务端回复 Stats
+
     Beat, // b"?" // beat
 }
 
+impl Msg2S {
+    // 带密码或 token 的帧不能打印到日志里
+    pub fn has_secret(&self) -> bool {
+        matches!(
+            self,
+            Self::Login { .. } | Self::Register { .. } | Self::Resume { .. }
+        )
+    }
+}
+
 impl FrameMsg for Msg2S {
     fn check(src: &mut Cursor<&[u8]>) -> Result<()> {
         match get_u8(src)? {
             b'>' => {
                 let start = src.position() as usize;
                 let end = src.get_ref().len();
-                if end - start < 24 {
+                if end - start < 32 {
                     Err(Error::Incomplete)
                 } else {
-                    skip(src, 16)?;
+                    skip(src, 24)?;
                     let len = src.get_u64();
                     skip(src, len as usize)
                 }
             }
-            b'l' => skip(src, 8),
-            b'p' | b'?' => Ok(()),
+            b'm' | b'M' | b'b' | b'B' | b'k' | b'g' => skip(src, 8),
+            b'K' | b'X' | b'U' => skip(src, 8),
+            b'c' | b'y' | b'x' | b'C' => skip(src, 8),
+            b'o' => skip(src, 1),
+            b'l' | b'R' => {
+                skip(src, 8)?;
+                skip_str(src)
+            }
+            b'p' => skip(src, 16),
+            b'h' => skip(src, 24),
+            b'd' => skip(src, 17),
+            b's' => {
+                skip_str(src)?;
+                skip(src, 49)
+            }
+            b'*' => {
+                skip(src, 8)?;
+                skip_u64s(src, b'*')?;
+                skip(src, 8)?;
+                skip_str(src)
+            }
+            b'!' => {
+                skip(src, 16)?;
+                skip_str(src)
+            }
+            b'+' | b'-' => {
+                skip(src, 8)?;
+                skip_str(src)
+            }
+            b'n' => {
+                skip_str(src)?;
+                skip_str(src)
+            }
+            b'T' | b'A' => skip_str(src),
+            b'L' | b'i' | b'q' | b'S' | b'?' => Ok(()),
             b => Err(Error::Invalid(b)),
         }
     }
@@ -162,6 +786,7 @@ impl FrameMsg for Msg2S {
             b'>' => {
                 let fake_msg_id = src.get_i64();
                 let to = src.get_u64();
+                let ttl = src.get_u64();
                 let len = src.get_u64();
                 let mut bytes = vec![0; len as usize];
                 src.read_exact(&mut bytes).unwrap();
@@ -170,14 +795,116 @@ impl FrameMsg for Msg2S {
                 Self::Msg {
                     fake_msg_id,
                     to,
+                    ttl,
                     len,
                     msg,
                 }
             }
             b'l' => Self::Login {
                 user_id: src.get_u64(),
+                password: read_str(src),
+            },
+            b'R' => Self::Register {
+                user_id: src.get_u64(),
+                password: read_str(src),
+            },
+            b'T' => Self::Resume {
+                token: read_str(src),
+            },
+            b'*' => Self::MultiMsg {
+                fake_msg_id: src.get_i64(),
+                to: read_u64s(src),
+                ttl: src.get_u64(),
+                msg: read_str(src),
+            },
+            b'!' => Self::Broadcast {
+                fake_msg_id: src.get_i64(),
+                ttl: src.get_u64(),
+                msg: read_str(src),
+            },
+            b'+' => Self::React {
+                msg_id: src.get_u64(),
+                emoji: read_str(src),
+            },
+            b'-' => Self::Unreact {
+                msg_id: src.get_u64(),
+                emoji: read_str(src),
+            },
+            b'm' => Self::Mute {
+                peer: src.get_u64(),
+            },
+            b'M' => Self::Unmute {
+                peer: src.get_u64(),
+            },
+            b'b' => Self::Block {
+                peer: src.get_u64(),
+            },
+            b'B' => Self::Unblock {
+                peer: src.get_u64(),
+            },
+            b'L' => Self::ListBlocked,
+            b'c' => Self::AddContact {
+                peer: src.get_u64(),
+            },
+            b'y' => Self::AcceptContact {
+                peer: src.get_u64(),
+            },
+            b'x' => Self::DeclineContact {
+                peer: src.get_u64(),
+            },
+            b'C' => Self::RemoveContact {
+                peer: src.get_u64(),
+            },
+            b'i' => Self::ListContacts,
+            b'o' => Self::ContactsOnly {
+                on: src.get_u8() != 0,
+            },
+            b'p' => Self::Pull {
+                since_msg_id: src.get_u64(),
+                limit: src.get_u64(),
+            },
+            b'h' => Self::History {
+                peer: src.get_u64(),
+                before_msg_id: src.get_u64(),
+                limit: src.get_u64(),
+            },
+            b's' => Self::Search {
+                query: read_str(src),
+                whole_word: src.get_u8() != 0,
+                sender: src.get_u64(),
+                peer: src.get_u64(),
+                since_ts: src.get_i64(),
+                until_ts: src.get_i64(),
+                before_msg_id: src.get_u64(),
+                limit: src.get_u64(),
+            },
+            b'd' => Self::Directory {
+                after_user_id: src.get_u64(),
+                online_only: src.get_u8() != 0,
+                limit: src.get_u64(),
+            },
+            b'n' => Self::SetProfile {
+                name: read_str(src),
+                status: read_str(src),
+            },
+            b'g' => Self::GetProfile {
+                user_id: src.get_u64(),
+            },
+            b'k' => Self::Ack {
+                msg_id: src.get_u64(),
+            },
+            b'q' => Self::Logout,
+            b'K' => Self::Kick {
+                user_id: src.get_u64(),
             },
-            b'p' => Self::Pull,
+            b'X' => Self::Ban {
+                user_id: src.get_u64(),
+            },
+            b'U' => Self::Unban {
+                user_id: src.get_u64(),
+            },
+            b'A' => Self::Announce { msg: read_str(src) },
+            b'S' => Self::Stats,
             b'?' => Self::Beat,
             _ => panic!("Please call check() first"),
         }
@@ -189,26 +916,287 @@ impl FrameMsg for Msg2S {
             Self::Msg {
                 fake_msg_id,
                 to,
+                ttl,
                 len,
                 msg,
             } => {
                 res.push(b'>');
                 res.extend(fake_msg_id.to_be_bytes());
                 res.extend(to.to_be_bytes());
+                res.extend(ttl.to_be_bytes());
                 res.extend(len.to_be_bytes());
                 res.extend(msg.bytes());
             }
-            Self::Login { user_id } => {
+            Self::Login { user_id, password } => {
                 res.push(b'l');
                 res.extend(user_id.to_be_bytes());
+                put_str(&mut res, password);
+            }
+            Self::Register { user_id, password } => {
+                res.push(b'R');
+                res.extend(user_id.to_be_bytes());
+                put_str(&mut res, password);
             }
-            Self::Pull => res.push(b'p'),
+            Self::Resume { token } => {
+                res.push(b'T');
+                put_str(&mut res, token);
+            }
+            Self::MultiMsg {
+                fake_msg_id,
+                to,
+                ttl,
+                msg,
+            } => {
+                res.push(b'*');
+                res.extend(fake_msg_id.to_be_bytes());
+                put_u64s(&mut res, to);
+                res.extend(ttl.to_be_bytes());
+                put_str(&mut res, msg);
+            }
+            Self::Broadcast {
+                fake_msg_id,
+                ttl,
+                msg,
+            } => {
+                res.push(b'!');
+                res.extend(fake_msg_id.to_be_bytes());
+                res.extend(ttl.to_be_bytes());
+                put_str(&mut res, msg);
+            }
+            Self::React { msg_id, emoji } => {
+                res.push(b'+');
+                res.extend(msg_id.to_be_bytes());
+                put_str(&mut res, emoji);
+            }
+            Self::Unreact { msg_id, emoji } => {
+                res.push(b'-');
+                res.extend(msg_id.to_be_bytes());
+                put_str(&mut res, emoji);
+            }
+            Self::Mute { peer } => {
+                res.push(b'm');
+                res.extend(peer.to_be_bytes());
+            }
+            Self::Unmute { peer } => {
+                res.push(b'M');
+                res.extend(peer.to_be_bytes());
+            }
+            Self::Block { peer } => {
+                res.push(b'b');
+                res.extend(peer.to_be_bytes());
+            }
+            Self::Unblock { peer } => {
+                res.push(b'B');
+                res.extend(peer.to_be_bytes());
+            }
+            Self::ListBlocked => res.push(b'L'),
+            Self::AddContact { peer } => {
+                res.push(b'c');
+                res.extend(peer.to_be_bytes());
+            }
+            Self::AcceptContact { peer } => {
+                res.push(b'y');
+                res.extend(peer.to_be_bytes());
+            }
+            Self::DeclineContact { peer } => {
+                res.push(b'x');
+                res.extend(peer.to_be_bytes());
+            }
+            Self::RemoveContact { peer } => {
+                res.push(b'C');
+                res.extend(peer.to_be_bytes());
+            }
+            Self::ListContacts => res.push(b'i'),
+            Self::ContactsOnly { on } => {
+                res.push(b'o');
+                res.push(*on as u8);
+            }
+            Self::Pull {
+                since_msg_id,
+                limit,
+            } => {
+                res.push(b'p');
+                res.extend(since_msg_id.to_be_bytes());
+                res.extend(limit.to_be_bytes());
+            }
+            Self::History {
+                peer,
+                before_msg_id,
+                limit,
+            } => {
+                res.push(b'h');
+                res.extend(peer.to_be_bytes());
+                res.extend(before_msg_id.to_be_bytes());
+                res.extend(limit.to_be_bytes());
+            }
+            Self::Search {
+                query,
+                whole_word,
+                sender,
+                peer,
+                since_ts,
+                until_ts,
+                before_msg_id,
+                limit,
+            } => {
+                res.push(b's');
+                put_str(&mut res, query);
+                res.push(*whole_word as u8);
+                res.extend(sender.to_be_bytes());
+                res.extend(peer.to_be_bytes());
+                res.extend(since_ts.to_be_bytes());
+                res.extend(until_ts.to_be_bytes());
+                res.extend(before_msg_id.to_be_bytes());
+                res.extend(limit.to_be_bytes());
+            }
+            Self::Directory {
+                after_user_id,
+                online_only,
+                limit,
+            } => {
+                res.push(b'd');
+                res.extend(after_user_id.to_be_bytes());
+                res.push(*online_only as u8);
+                res.extend(limit.to_be_bytes());
+            }
+            Self::SetProfile { name, status } => {
+                res.push(b'n');
+                put_str(&mut res, name);
+                put_str(&mut res, status);
+            }
+            Self::GetProfile { user_id } => {
+                res.push(b'g');
+                res.extend(user_id.to_be_bytes());
+            }
+            Self::Ack { msg_id } => {
+                res.push(b'k');
+                res.extend(msg_id.to_be_bytes());
+            }
+            Self::Logout => res.push(b'q'),
+            Self::Kick { user_id } => {
+                res.push(b'K');
+                res.extend(user_id.to_be_bytes());
+            }
+            Self::Ban { user_id } => {
+                res.push(b'X');
+                res.extend(user_id.to_be_bytes());
+            }
+            Self::Unban { user_id } => {
+                res.push(b'U');
+                res.extend(user_id.to_be_bytes());
+            }
+            Self::Announce { msg } => {
+                res.push(b'A');
+                put_str(&mut res, msg);
+            }
+            Self::Stats => res.push(b'S'),
             Self::Beat => res.push(b'?'),
         }
         res
     }
 }
 
+// 服务端保存的一条已发送的消息，历史查询的结果也用它
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub msg_id: u64,
+    pub from: u64,
+    pub to: u64,
+    pub ts: i64,
+    pub msg: String,
+}
+
+impl FrameMsg for Record {
+    fn check(src: &mut Cursor<&[u8]>) -> Result<()> {
+        skip(src, 32)?;
+        skip_str(src)
+    }
+
+    fn parse(src: &mut Cursor<&[u8]>) -> Self {
+        Self {
+            msg_id: src.get_u64(),
+            from: src.get_u64(),
+            to: src.get_u64(),
+            ts: src.get_i64(),
+            msg: read_str(src),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = vec![];
+        res.extend(self.msg_id.to_be_bytes());
+        res.extend(self.from.to_be_bytes());
+        res.extend(self.to.to_be_bytes());
+        res.extend(self.ts.to_be_bytes());
+        put_str(&mut res, &self.msg);
+        res
+    }
+}
+
+// 用户列表中的一项，name 为空表示没有设置
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserInfo {
+    pub user_id: u64,
+    pub name: String,
+    pub online: bool,
+}
+
+impl FrameMsg for UserInfo {
+    fn check(src: &mut Cursor<&[u8]>) -> Result<()> {
+        skip(src, 8)?;
+        skip_str(src)?;
+        skip(src, 1)
+    }
+
+    fn parse(src: &mut Cursor<&[u8]>) -> Self {
+        Self {
+            user_id: src.get_u64(),
+            name: read_str(src),
+            online: src.get_u8() != 0,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = vec![];
+        res.extend(self.user_id.to_be_bytes());
+        put_str(&mut res, &self.name);
+        res.push(self.online as u8);
+        res
+    }
+}
+
+// 用户资料，name 和 status 为空表示没有设置
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    pub user_id: u64,
+    pub name: String,
+    pub status: String,
+}
+
+impl FrameMsg for Profile {
+    fn check(src: &mut Cursor<&[u8]>) -> Result<()> {
+        skip(src, 8)?;
+        skip_str(src)?;
+        skip_str(src)
+    }
+
+    fn parse(src: &mut Cursor<&[u8]>) -> Self {
+        Self {
+            user_id: src.get_u64(),
+            name: read_str(src),
+            status: read_str(src),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = vec![];
+        res.extend(self.user_id.to_be_bytes());
+        put_str(&mut res, &self.name);
+        put_str(&mut res, &self.status);
+        res
+    }
+}
+
 // impl Msg2S {
 //     fn is_login_msg(src: &mut Cursor<&[u8]>) -> bool {
 //         matches!(peek_u8(src), Ok(b'l'))
@@ -227,14 +1215,21 @@ impl FrameMsg for Msg2S {
 //     Ok(src.chunk()[0])
 // }
 
-fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8> {
+pub(crate) fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8> {
     if !src.has_remaining() {
         return Err(Error::Incomplete);
     }
     Ok(src.get_u8())
 }
 
-fn skip(src: &mut Cursor<&[u8]>, n: usize) -> Result<()> {
+fn get_u64(src: &mut Cursor<&[u8]>) -> Result<u64> {
+    if src.remaining() < 8 {
+        return Err(Error::Incomplete);
+    }
+    Ok(src.get_u64())
+}
+
+pub(crate) fn skip(src: &mut Cursor<&[u8]>, n: usize) -> Result<()> {
     if src.remaining() < n {
         return Err(Error::Incomplete);
     }
@@ -242,6 +1237,66 @@ fn skip(src: &mut Cursor<&[u8]>, n: usize) -> Result<()> {
     Ok(())
 }
 
+// 变长字符串统一编码为 len(u64) + bytes
+pub(crate) fn skip_str(src: &mut Cursor<&[u8]>) -> Result<()> {
+    let len = get_u64(src)?;
+    skip(src, len as usize)
+}
+
+pub(crate) fn read_str(src: &mut Cursor<&[u8]>) -> String {
+    let len = src.get_u64();
+    let mut bytes = vec![0; len as usize];
+    src.read_exact(&mut bytes).unwrap(); // it's safe to unwrap() after check
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+pub(crate) fn put_str(res: &mut Vec<u8>, s: &str) {
+    res.extend((s.len() as u64).to_be_bytes());
+    res.extend(s.bytes());
+}
+
+// user_id 列表统一编码为 count(u64) + count 个 u64
+// count 是对面发来的，乘 8 溢出的话当作格式错误，ty 是所在帧的类型
+fn skip_u64s(src: &mut Cursor<&[u8]>, ty: u8) -> Result<()> {
+    let len = get_u64(src)?
+        .checked_mul(8)
+        .and_then(|len| usize::try_from(len).ok())
+        .ok_or(Error::Invalid(ty))?;
+    skip(src, len)
+}
+
+fn read_u64s(src: &mut Cursor<&[u8]>) -> Vec<u64> {
+    let n = src.get_u64();
+    (0..n).map(|_| src.get_u64()).collect()
+}
+
+fn put_u64s(res: &mut Vec<u8>, lst: &[u64]) {
+    res.extend((lst.len() as u64).to_be_bytes());
+    for x in lst {
+        res.extend(x.to_be_bytes());
+    }
+}
+
+// 嵌套的结构体列表编码为 count(u64) + 依次编码的每一项
+fn skip_list<T: FrameMsg>(src: &mut Cursor<&[u8]>) -> Result<()> {
+    for _ in 0..get_u64(src)? {
+        T::check(src)?;
+    }
+    Ok(())
+}
+
+fn read_list<T: FrameMsg>(src: &mut Cursor<&[u8]>) -> Vec<T> {
+    let n = src.get_u64();
+    (0..n).map(|_| T::parse(src)).collect()
+}
+
+fn put_list<T: FrameMsg>(res: &mut Vec<u8>, lst: &[T]) {
+    res.extend((lst.len() as u64).to_be_bytes());
+    for x in lst {
+        res.extend(x.to_bytes());
+    }
+}
+
 // fn write_decimal(val: u64) -> [u8; 8] {
 //     val.to_be_bytes()
 // }
@@ -275,25 +1330,154 @@ mod test {
                 msg_id: 1234,
                 from: 5678,
                 ts: get_current_timestamp(),
-                len: msg.bytes().len() as u64,
+                expire_at: 0,
+                len: msg.len() as u64,
                 msg,
             },
             Msg2C::Update {
                 fake_msg_id: -1,
                 real_msg_id: 99999,
             },
+            Msg2C::Reactions {
+                msg_id: 1234,
+                reactions: vec![("👍".to_string(), 2), ("🎉".to_string(), 1)],
+            },
+            Msg2C::Reactions {
+                msg_id: 1234,
+                reactions: vec![],
+            },
+            Msg2C::Mention {
+                msg_id: 1234,
+                from: 5678,
+                expire_at: get_current_timestamp() + 60,
+                msg: "@88888 look".to_string(),
+            },
+            Msg2C::Page {
+                last_msg_id: 99999,
+                has_more: true,
+                msgs: vec![
+                    Msg2C::Update {
+                        fake_msg_id: -1,
+                        real_msg_id: 99998,
+                    },
+                    Msg2C::Ok,
+                ],
+            },
+            Msg2C::History {
+                peer: 5678,
+                has_more: false,
+                records: vec![Record {
+                    msg_id: 1234,
+                    from: 5678,
+                    to: 88888,
+                    ts: get_current_timestamp(),
+                    msg: "hello world!".to_string(),
+                }],
+            },
+            Msg2C::SearchResult {
+                has_more: true,
+                records: vec![],
+            },
+            Msg2C::Directory {
+                has_more: false,
+                users: vec![
+                    UserInfo {
+                        user_id: 5678,
+                        name: String::new(),
+                        online: true,
+                    },
+                    UserInfo {
+                        user_id: 88888,
+                        name: "bob".to_string(),
+                        online: false,
+                    },
+                ],
+            },
+            Msg2C::Profile {
+                profile: Profile {
+                    user_id: 5678,
+                    name: "alice".to_string(),
+                    status: "busy".to_string(),
+                },
+            },
+            Msg2C::Session {
+                token: "0123abcd".to_string(),
+                expire_at: get_current_timestamp() + 3600,
+            },
+            Msg2C::Presence {
+                user_id: 5678,
+                online: false,
+            },
+            Msg2C::Blocked {
+                users: vec![5678, 88888],
+            },
+            Msg2C::Contacts {
+                contacts: vec![5678],
+                requests: vec![],
+                contacts_only: true,
+            },
+            Msg2C::ContactRequest { from: 5678 },
+            Msg2C::ContactAccepted { peer: 5678 },
+            Msg2C::Announce {
+                from: 1,
+                msg: "server will restart".to_string(),
+            },
+            Msg2C::Stats {
+                connected: 2,
+                users: 3,
+                msg_queue: 0,
+                offline_users: 1,
+                offline_msgs: 10,
+                banned: vec![5678],
+            },
             Msg2C::Quit,
             Msg2C::Ok,
-            Msg2C::Err,
+            Msg2C::Err {
+                code: ErrCode::NotFound,
+            },
+            Msg2C::Err {
+                code: ErrCode::Invalid,
+            },
+            Msg2C::Err {
+                code: ErrCode::AuthFailed,
+            },
+            Msg2C::Err {
+                code: ErrCode::ShuttingDown,
+            },
             Msg2C::AuthRequired,
         ];
 
         for item in lst {
             let bytes = item.to_bytes();
+            assert!(Msg2C::check(&mut Cursor::new(&bytes[..])).is_ok());
             let mut buf = Cursor::new(&bytes[..]);
             assert_eq!(item, Msg2C::parse(&mut buf));
         }
 
+        let msg = Msg2C::Msg {
+            msg_id: 1,
+            from: 2,
+            ts: 100,
+            expire_at: 130,
+            len: 0,
+            msg: String::new(),
+        };
+        assert!(!msg.is_expired(129));
+        assert!(msg.is_expired(130));
+        assert!(!Msg2C::Ok.is_expired(130));
+
+        // Page 内层的帧不完整是格式错误，而不是等待更多数据
+        let mut bytes = vec![b'p'];
+        bytes.extend(1u64.to_be_bytes());
+        bytes.push(0);
+        bytes.extend(1u64.to_be_bytes());
+        bytes.extend(1u64.to_be_bytes());
+        bytes.push(b'u');
+        assert!(matches!(
+            Msg2C::check(&mut Cursor::new(&bytes[..])),
+            Err(Error::Invalid(b'p'))
+        ));
+
         // assert!(Msg2C::check(&mut Cursor::new(b"l")).is_err());
         // assert!(Msg2C::check(&mut Cursor::new(b"e")).is_ok());
     }
@@ -305,23 +1489,138 @@ mod test {
             Msg2S::Msg {
                 fake_msg_id: -1234,
                 to: 5678,
-                len: msg.bytes().len() as u64,
+                ttl: 30,
+                len: msg.len() as u64,
                 msg,
             },
-            Msg2S::Login { user_id: 88888 },
-            Msg2S::Pull,
+            Msg2S::Login {
+                user_id: 88888,
+                password: "secret".to_string(),
+            },
+            Msg2S::Register {
+                user_id: 88888,
+                password: "secret".to_string(),
+            },
+            Msg2S::Resume {
+                token: "0123abcd".to_string(),
+            },
+            Msg2S::MultiMsg {
+                fake_msg_id: -1235,
+                to: vec![5678, 5679],
+                ttl: 0,
+                msg: "hello all".to_string(),
+            },
+            Msg2S::Broadcast {
+                fake_msg_id: -1236,
+                ttl: 0,
+                msg: "server will restart".to_string(),
+            },
+            Msg2S::React {
+                msg_id: 1234,
+                emoji: "👍".to_string(),
+            },
+            Msg2S::Unreact {
+                msg_id: 1234,
+                emoji: "👍".to_string(),
+            },
+            Msg2S::Mute { peer: 5678 },
+            Msg2S::Unmute { peer: 5678 },
+            Msg2S::Block { peer: 5678 },
+            Msg2S::Unblock { peer: 5678 },
+            Msg2S::ListBlocked,
+            Msg2S::AddContact { peer: 5678 },
+            Msg2S::AcceptContact { peer: 5678 },
+            Msg2S::DeclineContact { peer: 5678 },
+            Msg2S::RemoveContact { peer: 5678 },
+            Msg2S::ListContacts,
+            Msg2S::ContactsOnly { on: true },
+            Msg2S::Pull {
+                since_msg_id: 99999,
+                limit: 20,
+            },
+            Msg2S::Ack { msg_id: 99999 },
+            Msg2S::History {
+                peer: 5678,
+                before_msg_id: 0,
+                limit: 20,
+            },
+            Msg2S::Search {
+                query: "link".to_string(),
+                whole_word: true,
+                sender: 5678,
+                peer: 0,
+                since_ts: 1000,
+                until_ts: 0,
+                before_msg_id: 0,
+                limit: 20,
+            },
+            Msg2S::Directory {
+                after_user_id: 5678,
+                online_only: true,
+                limit: 20,
+            },
+            Msg2S::SetProfile {
+                name: "alice".to_string(),
+                status: String::new(),
+            },
+            Msg2S::GetProfile { user_id: 5678 },
+            Msg2S::Logout,
+            Msg2S::Kick { user_id: 5678 },
+            Msg2S::Ban { user_id: 5678 },
+            Msg2S::Unban { user_id: 5678 },
+            Msg2S::Announce {
+                msg: "server will restart".to_string(),
+            },
+            Msg2S::Stats,
             Msg2S::Beat,
         ];
 
         for item in lst {
             let bytes = item.to_bytes();
+            assert!(Msg2S::check(&mut Cursor::new(&bytes[..])).is_ok());
+            assert!(Msg2S::check(&mut Cursor::new(&bytes[..bytes.len() - 1])).is_err());
             let mut buf = Cursor::new(&bytes[..]);
             assert_eq!(item, Msg2S::parse(&mut buf));
         }
 
         assert!(Msg2S::check(&mut Cursor::new(b"e")).is_err());
         assert!(Msg2S::check(&mut Cursor::new(b"l")).is_err());
-        assert!(Msg2S::check(&mut Cursor::new(b"p")).is_ok());
+        assert!(Msg2S::check(&mut Cursor::new(b"?")).is_ok());
+
+        // 接收者个数乘 8 会溢出的 MultiMsg 是格式错误
+        let mut bytes = vec![b'*'];
+        bytes.extend(1i64.to_be_bytes());
+        bytes.extend((1u64 << 61).to_be_bytes());
+        assert!(matches!(
+            Msg2S::check(&mut Cursor::new(&bytes[..])),
+            Err(Error::Invalid(b'*'))
+        ));
+    }
+
+    #[test]
+    fn test_has_secret() {
+        let msg = |expire_at| Msg2C::Msg {
+            msg_id: 1,
+            from: 2,
+            ts: 0,
+            expire_at,
+            len: 2,
+            msg: "hi".to_string(),
+        };
+        assert!(!msg(0).has_secret());
+        assert!(msg(100).has_secret());
+        assert!(Msg2C::Session {
+            token: "t".to_string(),
+            expire_at: 0,
+        }
+        .has_secret());
+        let page = |msgs| Msg2C::Page {
+            last_msg_id: 1,
+            has_more: false,
+            msgs,
+        };
+        assert!(!page(vec![msg(0)]).has_secret());
+        assert!(page(vec![msg(0), msg(100)]).has_secret());
     }
 
     // #[test]
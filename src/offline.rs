@@ -0,0 +1,189 @@
+use crate::error::Result;
+use crate::id::IdAllocator;
+use crate::msg::{skip, FrameMsg, Msg2C};
+use bytes::Buf;
+use std::collections::HashMap;
+use std::io::{self, Cursor};
+use std::sync::Arc;
+
+// 关闭服务端时还没 ack 的离线消息，存到文件里，下次启动时再放回 OfflineStore
+#[derive(Debug, Clone, PartialEq)]
+pub struct OfflineMsg {
+    pub user_id: u64,
+    pub msg: Msg2C,
+}
+
+impl FrameMsg for OfflineMsg {
+    fn check(src: &mut Cursor<&[u8]>) -> Result<()> {
+        skip(src, 8)?;
+        Msg2C::check(src)
+    }
+
+    fn parse(src: &mut Cursor<&[u8]>) -> Self {
+        Self {
+            user_id: src.get_u64(),
+            msg: Msg2C::parse(src),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = vec![];
+        res.extend(self.user_id.to_be_bytes());
+        res.extend(self.msg.to_bytes());
+        res
+    }
+}
+
+// 离线消息，每条消息存入时分配一个全局 id 作为 cursor, 所以每个用户的消息都按 cursor 有序
+// cursor 和 msg_id 分开分配，但也要持久化，重启之后客户端还会拿着之前的 cursor 来 pull
+// 客户端按 cursor 分页 pull, 消息只有在 ack 之后才会删除
+pub struct OfflineStore {
+    cursors: Arc<IdAllocator>,
+    boxes: HashMap<u64, Vec<(u64, Msg2C)>>,
+}
+
+impl OfflineStore {
+    pub fn new(cursors: Arc<IdAllocator>) -> Self {
+        Self {
+            cursors,
+            boxes: HashMap::new(),
+        }
+    }
+
+    // 分配不了 cursor 时返回错误，消息没有存下来
+    pub fn push(&mut self, user_id: u64, msg: Msg2C) -> io::Result<()> {
+        // 在锁内分配 cursor, 保证 cursor 和插入顺序一致
+        let cursor = self.cursors.next()?;
+        let pq = self.boxes.entry(user_id).or_default();
+        // reaction 只保留最新的汇总，pull 时看到的是当前状态而不是每次增减的回放
+        if let Msg2C::Reactions { msg_id, .. } = &msg {
+            pq.retain(|(_, m)| !matches!(m, Msg2C::Reactions { msg_id: id, .. } if id == msg_id));
+        }
+        pq.push((cursor, msg));
+        Ok(())
+    }
+
+    // cursor 大于 since 的前 limit 条消息，以及后面是否还有
+    pub fn page(&self, user_id: u64, since: u64, limit: usize) -> (Vec<(u64, Msg2C)>, bool) {
+        match self.boxes.get(&user_id) {
+            Some(pq) => {
+                let start = pq.partition_point(|(cursor, _)| *cursor <= since);
+                let end = pq.len().min(start + limit);
+                (pq[start..end].to_vec(), end < pq.len())
+            }
+            None => (vec![], false),
+        }
+    }
+
+    // 删除 cursor 不大于 msg_id 的消息
+    pub fn ack(&mut self, user_id: u64, msg_id: u64) {
+        if let Some(pq) = self.boxes.get_mut(&user_id) {
+            pq.retain(|(cursor, _)| *cursor > msg_id);
+            if pq.is_empty() {
+                self.boxes.remove(&user_id);
+            }
+        }
+    }
+
+    // 有离线消息的用户数和离线消息总数
+    pub fn stats(&self) -> (usize, usize) {
+        (
+            self.boxes.len(),
+            self.boxes.values().map(|pq| pq.len()).sum(),
+        )
+    }
+
+    // 所有离线消息，每个用户的按 cursor 的顺序，重新 push 之后顺序不变
+    pub fn dump(&self) -> Vec<OfflineMsg> {
+        self.boxes
+            .iter()
+            .flat_map(|(&user_id, pq)| {
+                pq.iter().map(move |(_, msg)| OfflineMsg {
+                    user_id,
+                    msg: msg.clone(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn expire(&mut self, now: i64) {
+        self.boxes.retain(|_, pq| {
+            pq.retain(|(_, msg)| !msg.is_expired(now));
+            !pq.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage;
+    use crate::testutil::TempDir;
+
+    fn reactions(msg_id: u64, count: u64) -> Msg2C {
+        Msg2C::Reactions {
+            msg_id,
+            reactions: vec![("👍".to_string(), count)],
+        }
+    }
+
+    #[test]
+    fn test_page_and_ack() {
+        let mut store = OfflineStore::new(Arc::new(IdAllocator::new(None, false).unwrap()));
+        for i in 0..5 {
+            store.push(1, reactions(i, 1)).unwrap();
+        }
+
+        let (page, has_more) = store.page(1, 0, 2);
+        assert_eq!(page.len(), 2);
+        assert!(has_more);
+
+        let last = page.last().unwrap().0;
+        let (page, has_more) = store.page(1, last, 10);
+        assert_eq!(page.len(), 3);
+        assert!(!has_more);
+
+        // 没 ack 之前可以重新拉取
+        assert_eq!(store.page(1, 0, 10).0.len(), 5);
+        store.ack(1, last);
+        assert_eq!(store.page(1, 0, 10).0.len(), 3);
+        assert_eq!(store.stats(), (1, 3));
+        assert_eq!(store.page(2, 0, 10), (vec![], false));
+    }
+
+    #[test]
+    fn test_coalesce_reactions() {
+        let mut store = OfflineStore::new(Arc::new(IdAllocator::new(None, false).unwrap()));
+        store.push(1, reactions(7, 1)).unwrap();
+        store.push(1, reactions(8, 1)).unwrap();
+        store.push(1, reactions(7, 2)).unwrap();
+
+        let msgs: Vec<Msg2C> = store.page(1, 0, 10).0.into_iter().map(|(_, m)| m).collect();
+        assert_eq!(msgs, vec![reactions(8, 1), reactions(7, 2)]);
+    }
+
+    #[test]
+    fn test_dump() {
+        let cursors = Arc::new(IdAllocator::new(None, false).unwrap());
+        let mut store = OfflineStore::new(cursors.clone());
+        store.push(1, reactions(7, 1)).unwrap();
+        store.push(2, Msg2C::Ok).unwrap();
+        store.push(1, reactions(8, 1)).unwrap();
+
+        let dir = TempDir::new();
+        let path = dir.file("offline");
+        storage::save(&path, &store.dump()).unwrap();
+        let mut restored = OfflineStore::new(cursors);
+        for OfflineMsg { user_id, msg } in storage::load(&path).unwrap() {
+            restored.push(user_id, msg).unwrap();
+        }
+        let msgs: Vec<Msg2C> = restored
+            .page(1, 0, 10)
+            .0
+            .into_iter()
+            .map(|(_, m)| m)
+            .collect();
+        assert_eq!(msgs, vec![reactions(7, 1), reactions(8, 1)]);
+        assert_eq!(restored.stats(), (2, 3));
+    }
+}
@@ -0,0 +1,84 @@
+use crate::msg::FrameMsg;
+use crate::storage;
+use std::io;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+
+// 操作应用到内存里的状态上，返回状态是否有变化
+pub trait Apply<T> {
+    fn apply(&mut self, op: &T) -> bool;
+}
+
+// 追加写的操作日志：每个操作先应用到状态上，有变化才追加写到文件里，启动时按顺序重放
+// 屏蔽、封禁、联系人、资料和账号都是这样存的，各自只要实现 Apply
+pub struct OpLog<T, S> {
+    state: S,
+    path: Option<PathBuf>,
+    _op: PhantomData<T>,
+}
+
+impl<T: FrameMsg, S: Apply<T> + Default> OpLog<T, S> {
+    pub fn open(path: Option<PathBuf>) -> io::Result<Self> {
+        let mut state = S::default();
+        if let Some(path) = &path {
+            for op in storage::load::<T>(path)? {
+                state.apply(&op);
+            }
+        }
+        Ok(Self {
+            state,
+            path,
+            _op: PhantomData,
+        })
+    }
+
+    // 返回状态是否有变化，没变化的不写文件
+    pub fn push(&mut self, op: T) -> io::Result<bool> {
+        if !self.state.apply(&op) {
+            return Ok(false);
+        }
+        if let Some(path) = &self.path {
+            storage::append(path, &op)?;
+        }
+        Ok(true)
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ban::BanOp;
+    use crate::testutil::TempDir;
+
+    // 只数一共封禁过几次，解封不算变化
+    #[derive(Default)]
+    struct Count(u64);
+
+    impl Apply<BanOp> for Count {
+        fn apply(&mut self, op: &BanOp) -> bool {
+            self.0 += op.banned as u64;
+            op.banned
+        }
+    }
+
+    #[test]
+    fn test_oplog() {
+        let dir = TempDir::new();
+        let path = dir.file("oplog");
+
+        let mut log: OpLog<BanOp, Count> = OpLog::open(Some(path.clone())).unwrap();
+        for banned in [true, false, true] {
+            log.push(BanOp { user_id: 1, banned }).unwrap();
+        }
+        assert_eq!(log.state().0, 2);
+        drop(log); // 模拟重启
+
+        let log: OpLog<BanOp, Count> = OpLog::open(Some(path.clone())).unwrap();
+        assert_eq!(log.state().0, 2);
+        assert_eq!(storage::load::<BanOp>(&path).unwrap().len(), 2); // 没变化的没写
+    }
+}
@@ -0,0 +1,97 @@
+use crate::msg::Profile;
+use crate::oplog::{Apply, OpLog};
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+
+pub const NAME_MAX: usize = 32; // 按字符数算
+pub const STATUS_MAX: usize = 140;
+
+// 服务端保存的用户资料，追加写到文件里，读回来时后写的覆盖先写的
+pub struct Profiles {
+    log: OpLog<Profile, HashMap<u64, Profile>>,
+}
+
+impl Apply<Profile> for HashMap<u64, Profile> {
+    fn apply(&mut self, profile: &Profile) -> bool {
+        self.insert(profile.user_id, profile.clone()).as_ref() != Some(profile)
+    }
+}
+
+impl Profiles {
+    pub fn open(path: Option<PathBuf>) -> io::Result<Self> {
+        Ok(Self {
+            log: OpLog::open(path)?,
+        })
+    }
+
+    // 和原来一样的资料不写文件
+    pub fn set(&mut self, profile: Profile) -> io::Result<()> {
+        self.log.push(profile)?;
+        Ok(())
+    }
+
+    // 没有设置过的用户返回空的资料
+    pub fn get(&self, user_id: u64) -> Profile {
+        self.log.state().get(&user_id).cloned().unwrap_or(Profile {
+            user_id,
+            ..Default::default()
+        })
+    }
+
+    pub fn name(&self, user_id: u64) -> &str {
+        self.log
+            .state()
+            .get(&user_id)
+            .map_or("", |p| p.name.as_str())
+    }
+}
+
+// 名字不能太长，也不能有换行之类的控制字符，否则客户端显示会乱
+pub fn is_valid(name: &str, status: &str) -> bool {
+    name.chars().count() <= NAME_MAX
+        && status.chars().count() <= STATUS_MAX
+        && !name.chars().chain(status.chars()).any(char::is_control)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn profile(user_id: u64, name: &str) -> Profile {
+        Profile {
+            user_id,
+            name: name.to_string(),
+            status: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_profiles() {
+        let dir = TempDir::new();
+        let path = dir.file("profiles");
+
+        let mut profiles = Profiles::open(Some(path.clone())).unwrap();
+        assert_eq!(profiles.get(1), profile(1, ""));
+        profiles.set(profile(1, "alice")).unwrap();
+        profiles.set(profile(2, "bob")).unwrap();
+        profiles.set(profile(1, "Alice")).unwrap();
+        drop(profiles); // 模拟重启
+
+        let profiles = Profiles::open(Some(path.clone())).unwrap();
+        assert_eq!(profiles.name(1), "Alice");
+        assert_eq!(profiles.name(2), "bob");
+        assert_eq!(profiles.name(3), "");
+    }
+
+    #[test]
+    fn test_is_valid() {
+        assert!(is_valid("alice", "busy"));
+        assert!(is_valid("", ""));
+        assert!(is_valid(&"名".repeat(NAME_MAX), ""));
+        assert!(!is_valid(&"a".repeat(NAME_MAX + 1), ""));
+        assert!(!is_valid("alice\nbob", ""));
+        assert!(!is_valid("alice", &"a".repeat(STATUS_MAX + 1)));
+    }
+}
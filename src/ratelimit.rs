@@ -0,0 +1,132 @@
+use std::time::Instant;
+
+// 令牌桶：每秒补充 rate 个令牌，最多攒 burst 个，rate 为 0 表示不限制
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    // 比 burst 还大的请求在桶满时也放行，欠下的令牌之后慢慢还，否则大消息永远发不出去
+    fn has(&self, n: f64) -> bool {
+        self.rate == 0.0 || self.tokens >= n.min(self.burst)
+    }
+
+    fn consume(&mut self, n: f64) {
+        if self.rate != 0.0 {
+            self.tokens -= n;
+        }
+    }
+
+    pub fn take(&mut self, n: f64, now: Instant) -> bool {
+        self.refill(now);
+        let ok = self.has(n);
+        if ok {
+            self.consume(n);
+        }
+        ok
+    }
+
+    // 桶满了就和新建的一样，丢掉也不会让谁多发
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.burst
+    }
+}
+
+// 每秒的消息数和字节数各一个桶，两个都够才放行，burst 都是一秒的量
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    msgs: TokenBucket,
+    bytes: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(msgs_per_sec: u64, bytes_per_sec: u64, now: Instant) -> Self {
+        let msgs = msgs_per_sec as f64;
+        let bytes = bytes_per_sec as f64;
+        Self {
+            msgs: TokenBucket::new(msgs, msgs, now),
+            bytes: TokenBucket::new(bytes, bytes, now),
+        }
+    }
+
+    // 一个帧有 len 个字节，被拒绝时不消耗令牌
+    pub fn check(&mut self, len: usize, now: Instant) -> bool {
+        self.msgs.refill(now);
+        self.bytes.refill(now);
+        let ok = self.msgs.has(1.0) && self.bytes.has(len as f64);
+        if ok {
+            self.msgs.consume(1.0);
+            self.bytes.consume(len as f64);
+        }
+        ok
+    }
+
+    pub fn is_idle(&mut self, now: Instant) -> bool {
+        self.msgs.is_full(now) && self.bytes.is_full(now)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 4.0, now);
+        assert!((0..4).all(|_| bucket.take(1.0, now)));
+        assert!(!bucket.take(1.0, now));
+        assert!(bucket.take(1.0, now + Duration::from_millis(500)));
+        assert!(!bucket.take(1.0, now + Duration::from_millis(500)));
+        // 最多只攒 burst 个
+        let later = now + Duration::from_secs(60);
+        assert!((0..4).all(|_| bucket.take(1.0, later)));
+        assert!(!bucket.take(1.0, later));
+
+        let mut unlimited = TokenBucket::new(0.0, 0.0, now);
+        assert!((0..100).all(|_| unlimited.take(1000.0, now)));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(10, 100, now);
+        assert!(limiter.check(60, now));
+        assert!(!limiter.check(60, now)); // 字节数超了
+        assert!(limiter.check(40, now));
+
+        // 大于 burst 的帧在桶满时可以发，之后要等令牌还清
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.check(250, later));
+        assert!(!limiter.check(1, later + Duration::from_secs(1)));
+        assert!(limiter.check(1, later + Duration::from_secs(3)));
+        assert!(!limiter.is_idle(later + Duration::from_secs(3)));
+        assert!(limiter.is_idle(later + Duration::from_secs(4)));
+
+        let mut limiter = RateLimiter::new(2, 0, now);
+        assert!(limiter.check(10_000, now));
+        assert!(limiter.check(10_000, now));
+        assert!(!limiter.check(1, now)); // 消息数超了
+    }
+}
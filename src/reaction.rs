@@ -0,0 +1,141 @@
+use crate::msg::ErrCode;
+use std::collections::{BTreeMap, BTreeSet};
+
+pub const EMOJI_MAX: usize = 32; // 按字节算，带肤色、ZWJ 的组合 emoji 也放得下
+pub const EMOJI_KINDS_MAX: usize = 20; // 一条消息上最多有几种不同的 emoji
+
+// 服务端保存的某条消息的 reaction 状态
+// 按 emoji 记录是谁点的，这样重复 add 或 remove 都是幂等的
+#[derive(Debug)]
+pub struct Reactions {
+    participants: Vec<u64>, // 消息的发送者和接收者
+    users: BTreeMap<String, BTreeSet<u64>>,
+}
+
+impl Reactions {
+    pub fn new(mut participants: Vec<u64>) -> Self {
+        participants.sort_unstable();
+        participants.dedup(); // 自己给自己发消息
+        Self {
+            participants,
+            users: BTreeMap::new(),
+        }
+    }
+
+    pub fn participants(&self) -> &[u64] {
+        &self.participants
+    }
+
+    pub fn is_participant(&self, user_id: u64) -> bool {
+        self.participants.contains(&user_id)
+    }
+
+    // 返回状态是否有变化，emoji 为空、太长或者有控制字符，或者种类已经满了，返回 Invalid
+    // 否则每次换一个字符串就能让服务端多存一份，汇总也越来越大
+    pub fn add(&mut self, user_id: u64, emoji: &str) -> Result<bool, ErrCode> {
+        if emoji.is_empty() || emoji.len() > EMOJI_MAX || emoji.chars().any(char::is_control) {
+            return Err(ErrCode::Invalid);
+        }
+        if !self.users.contains_key(emoji) && self.users.len() >= EMOJI_KINDS_MAX {
+            return Err(ErrCode::Invalid);
+        }
+        Ok(self
+            .users
+            .entry(emoji.to_string())
+            .or_default()
+            .insert(user_id))
+    }
+
+    pub fn remove(&mut self, user_id: u64, emoji: &str) -> bool {
+        if let Some(users) = self.users.get_mut(emoji) {
+            let removed = users.remove(&user_id);
+            if users.is_empty() {
+                self.users.remove(emoji);
+            }
+            removed
+        } else {
+            false
+        }
+    }
+
+    pub fn counts(&self) -> Vec<(String, u64)> {
+        self.users
+            .iter()
+            .map(|(emoji, users)| (emoji.clone(), users.len() as u64))
+            .collect()
+    }
+}
+
+// 最近 capacity 条消息的 reaction 状态，key 为 real_msg_id
+// msg_id 是递增的，满了就淘汰最早的消息，之后再 react 它会找不到
+pub struct RecentReactions {
+    msgs: BTreeMap<u64, Reactions>,
+    capacity: usize,
+}
+
+impl RecentReactions {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            msgs: BTreeMap::new(),
+            capacity,
+        }
+    }
+
+    pub fn insert(&mut self, msg_id: u64, reactions: Reactions) {
+        self.msgs.insert(msg_id, reactions);
+        while self.msgs.len() > self.capacity {
+            self.msgs.pop_first();
+        }
+    }
+
+    pub fn get_mut(&mut self, msg_id: u64) -> Option<&mut Reactions> {
+        self.msgs.get_mut(&msg_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reactions() {
+        let mut r = Reactions::new(vec![1, 2]);
+        assert!(r.is_participant(2));
+        assert!(!r.is_participant(3));
+
+        assert_eq!(r.add(1, "👍"), Ok(true));
+        assert_eq!(r.add(1, "👍"), Ok(false)); // 重复点不会累加
+        assert_eq!(r.add(2, "👍"), Ok(true));
+        assert_eq!(r.add(2, "🎉"), Ok(true));
+        assert_eq!(
+            r.counts(),
+            vec![("🎉".to_string(), 1), ("👍".to_string(), 2)]
+        );
+
+        assert!(r.remove(2, "🎉"));
+        assert!(!r.remove(2, "🎉"));
+        assert!(!r.remove(3, "👍"));
+        assert_eq!(r.counts(), vec![("👍".to_string(), 2)]);
+
+        assert_eq!(r.add(1, ""), Err(ErrCode::Invalid));
+        assert_eq!(r.add(1, &"👍".repeat(9)), Err(ErrCode::Invalid));
+        assert_eq!(r.add(1, "a\nb"), Err(ErrCode::Invalid));
+        for i in 1..EMOJI_KINDS_MAX {
+            assert_eq!(r.add(1, &i.to_string()), Ok(true));
+        }
+        assert_eq!(r.add(1, "🎉"), Err(ErrCode::Invalid)); // 种类满了
+        assert_eq!(r.add(2, "👍"), Ok(false)); // 已有的还能点
+        assert_eq!(r.add(2, "1"), Ok(true));
+    }
+
+    #[test]
+    fn test_recent_reactions() {
+        let mut recent = RecentReactions::new(2);
+        for msg_id in [1, 3, 2] {
+            recent.insert(msg_id, Reactions::new(vec![1, 2]));
+        }
+        assert!(recent.get_mut(1).is_none()); // 最早的被淘汰了
+        assert!(recent.get_mut(2).is_some());
+        assert_eq!(recent.get_mut(3).unwrap().add(1, "👍"), Ok(true));
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+// 在线用户的连接表：user_id -> 这个用户的每个连接，同一个用户可以有多个设备，用对端地址区分
+// 一个用户的连接都移除了就把这个用户也删掉，所以有 key 的用户都是在线的
+pub struct Registry<T> {
+    dict: HashMap<u64, HashMap<SocketAddr, T>>,
+}
+
+impl<T> Default for Registry<T> {
+    fn default() -> Self {
+        Self {
+            dict: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Registry<T> {
+    pub fn is_online(&self, user_id: u64) -> bool {
+        self.dict.contains_key(&user_id)
+    }
+
+    pub fn users(&self) -> Vec<u64> {
+        self.dict.keys().copied().collect()
+    }
+
+    // 连接数，不是用户数
+    pub fn len(&self) -> usize {
+        self.dict.values().map(|clients| clients.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dict.is_empty()
+    }
+
+    // 所有用户的所有连接
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.dict.values().flat_map(|clients| clients.values())
+    }
+
+    pub fn clients(&self, user_id: u64) -> Option<&HashMap<SocketAddr, T>> {
+        self.dict.get(&user_id)
+    }
+
+    pub fn get_mut(&mut self, user_id: u64, addr: SocketAddr) -> Option<&mut T> {
+        self.dict.get_mut(&user_id)?.get_mut(&addr)
+    }
+
+    // 返回是不是这个用户的第一个连接，也就是要不要通知其他人上线了
+    pub fn insert(&mut self, user_id: u64, addr: SocketAddr, client: T) -> bool {
+        let clients = self.dict.entry(user_id).or_default();
+        let first = clients.is_empty();
+        clients.insert(addr, client);
+        first
+    }
+
+    pub fn remove(&mut self, user_id: u64, addr: SocketAddr) -> Option<T> {
+        let clients = self.dict.get_mut(&user_id)?;
+        let client = clients.remove(&addr);
+        if clients.is_empty() {
+            self.dict.remove(&user_id);
+        }
+        client
+    }
+
+    pub fn remove_user(&mut self, user_id: u64) -> Vec<T> {
+        self.dict
+            .remove(&user_id)
+            .map_or(vec![], |clients| clients.into_values().collect())
+    }
+
+    // 已登录的连接切换账号：把 addr 这个连接从 from 移到 to 下面
+    // 这个连接不在 from 下面的话返回 None, 否则返回 (from 是否没有连接了, 是不是 to 的第一个连接)
+    pub fn switch(&mut self, from: u64, to: u64, addr: SocketAddr) -> Option<(bool, bool)> {
+        let client = self.remove(from, addr)?;
+        let from_offline = !self.is_online(from);
+        Some((from_offline, self.insert(to, addr, client)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = Registry::default();
+        assert!(registry.insert(1, addr(1000), "a"));
+        assert!(!registry.insert(1, addr(1001), "b")); // 第二个设备
+        assert!(registry.insert(2, addr(1002), "c"));
+        assert_eq!(registry.len(), 3);
+        let mut values: Vec<&str> = registry.values().copied().collect();
+        values.sort();
+        assert_eq!(values, vec!["a", "b", "c"]);
+
+        assert_eq!(registry.remove(1, addr(1002)), None); // 不是 1 的连接
+        assert_eq!(registry.remove(1, addr(1000)), Some("a"));
+        assert!(registry.is_online(1));
+        assert_eq!(registry.remove(1, addr(1001)), Some("b"));
+        assert!(!registry.is_online(1));
+        assert_eq!(registry.users(), vec![2]);
+
+        assert_eq!(registry.remove_user(2), vec!["c"]);
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn test_registry_switch() {
+        let mut registry = Registry::default();
+        registry.insert(1, addr(1000), "a");
+        registry.insert(1, addr(1001), "b");
+        registry.insert(2, addr(1002), "c");
+
+        // 1 还有另一个设备在线，2 之前就在线
+        assert_eq!(registry.switch(1, 2, addr(1000)), Some((false, false)));
+        assert_eq!(registry.clients(2).unwrap().len(), 2);
+        // 1 的最后一个连接切到了新用户 3
+        assert_eq!(registry.switch(1, 3, addr(1001)), Some((true, true)));
+        assert!(!registry.is_online(1));
+        assert_eq!(registry.get_mut(3, addr(1001)), Some(&mut "b"));
+        // 不是自己的连接不能切换
+        assert_eq!(registry.switch(1, 3, addr(1002)), None);
+        assert_eq!(registry.switch(3, 2, addr(1002)), None);
+        assert_eq!(registry.len(), 3);
+    }
+}
@@ -0,0 +1,95 @@
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::HashMap;
+
+// 登录成功后发给客户端的会话 token, 断线重连时用它恢复会话而不用再输一次密码
+// 只保存在内存里，服务端重启后需要重新登录
+pub struct Sessions {
+    ttl: i64,
+    dict: HashMap<String, (u64, i64)>, // token -> (user_id, expire_at)
+}
+
+impl Sessions {
+    pub fn new(ttl: i64) -> Self {
+        Self {
+            ttl,
+            dict: HashMap::new(),
+        }
+    }
+
+    pub fn issue(&mut self, user_id: u64, now: i64) -> (String, i64) {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let expire_at = now.saturating_add(self.ttl);
+        self.dict.insert(token.clone(), (user_id, expire_at));
+        (token, expire_at)
+    }
+
+    // 成功的话顺延过期时间，返回 (user_id, 新的 expire_at)
+    pub fn resume(&mut self, token: &str, now: i64) -> Option<(u64, i64)> {
+        match self.dict.get_mut(token) {
+            Some((user_id, expire_at)) if *expire_at > now => {
+                *expire_at = now.saturating_add(self.ttl);
+                Some((*user_id, *expire_at))
+            }
+            Some(_) => {
+                self.dict.remove(token);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn revoke(&mut self, token: &str) -> Option<u64> {
+        self.dict.remove(token).map(|(user_id, _)| user_id)
+    }
+
+    // 用户的所有 token 都失效，比如修改密码或者被管理员踢下线
+    pub fn revoke_user(&mut self, user_id: u64) {
+        self.dict.retain(|_, (id, _)| *id != user_id);
+    }
+
+    pub fn expire(&mut self, now: i64) {
+        self.dict.retain(|_, (_, expire_at)| *expire_at > now);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sessions() {
+        let mut sessions = Sessions::new(100);
+        let (token, expire_at) = sessions.issue(1, 1000);
+        assert_eq!(token.len(), 64);
+        assert_eq!(expire_at, 1100);
+        assert_ne!(sessions.issue(1, 1000).0, token);
+
+        assert_eq!(sessions.resume(&token, 1050), Some((1, 1150)));
+        assert_eq!(sessions.resume(&token, 1149), Some((1, 1249))); // 顺延过
+        assert_eq!(sessions.resume(&token, 1249), None);
+        assert_eq!(sessions.resume(&token, 0), None); // 过期的已经删掉了
+        assert_eq!(sessions.resume("bad", 0), None);
+
+        let (token, _) = sessions.issue(2, 1000);
+        assert_eq!(sessions.revoke(&token), Some(2));
+        assert_eq!(sessions.resume(&token, 1000), None);
+
+        let (a, _) = sessions.issue(3, 1000);
+        let (b, _) = sessions.issue(3, 1000);
+        let (c, _) = sessions.issue(4, 1000);
+        sessions.revoke_user(3);
+        assert_eq!(sessions.resume(&a, 1000), None);
+        assert_eq!(sessions.resume(&b, 1000), None);
+        sessions.expire(1100);
+        assert_eq!(sessions.resume(&c, 1000), None);
+
+        // ttl 太大时不会溢出
+        let mut sessions = Sessions::new(i64::MAX);
+        let (token, expire_at) = sessions.issue(1, 1000);
+        assert_eq!(expire_at, i64::MAX);
+        assert_eq!(sessions.resume(&token, 2000), Some((1, i64::MAX)));
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::{Mutex, MutexGuard};
+
+// 按 key (user_id 或 msg_id) 分成多个分片，每个分片一把锁，和 ServerState 一样
+// 投递时要查的各种按用户、按消息的表都用它，不同的 key 大多落在不同分片上，不会都等同一把锁
+pub struct Sharded<T> {
+    shards: Vec<Mutex<T>>,
+}
+
+impl<T> Sharded<T> {
+    // 分片数至少为 1, 为 1 时就相当于全局锁
+    pub fn new(shards: usize, mut f: impl FnMut() -> T) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| Mutex::new(f())).collect(),
+        }
+    }
+
+    // key 所在的分片，同一个分片里还有其他 key, 不要拿着它再锁别的分片
+    pub fn lock(&self, key: u64) -> MutexGuard<'_, T> {
+        self.shards[(key % self.shards.len() as u64) as usize]
+            .lock()
+            .unwrap()
+    }
+
+    // 逐个分片加锁
+    pub fn for_each(&self, mut f: impl FnMut(&mut T)) {
+        for shard in &self.shards {
+            f(&mut shard.lock().unwrap());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_sharded() {
+        let dict: Sharded<HashMap<u64, u64>> = Sharded::new(4, HashMap::new);
+        for key in 0..10 {
+            dict.lock(key).insert(key, key * 10);
+        }
+        assert_eq!(dict.lock(5).get(&5), Some(&50));
+        assert_eq!(dict.lock(5).get(&6), None); // 不在同一个分片
+        assert_eq!(dict.lock(1).len(), 3); // 1, 5, 9
+
+        let mut n = 0;
+        dict.for_each(|shard| n += shard.len());
+        assert_eq!(n, 10);
+    }
+}
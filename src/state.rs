@@ -0,0 +1,189 @@
+use crate::id::IdAllocator;
+use crate::offline::{OfflineMsg, OfflineStore};
+use crate::registry::Registry;
+use crate::storage;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// 服务端按用户划分的状态：在线连接表和离线消息，按 user_id 分成多个分片，每个分片一把锁
+// 只涉及一个用户的操作 (投递、pull、ack、登录) 只锁他所在的分片，不同用户之间基本不会互相等
+// 需要看所有用户的操作 (在线列表、统计) 逐个分片加锁，拿到的不是同一时刻的快照，不过够用了
+// T 是服务端的连接类型
+pub struct ServerState<T> {
+    connected: Vec<Mutex<Registry<T>>>,
+    offline: Vec<Mutex<OfflineStore>>,
+}
+
+impl<T> ServerState<T> {
+    // 分片数至少为 1, 为 1 时就相当于全局锁
+    // cursors 用来给离线消息分配 cursor
+    pub fn new(shards: usize, cursors: Arc<IdAllocator>) -> Self {
+        let shards = shards.max(1);
+        Self {
+            connected: (0..shards).map(|_| Default::default()).collect(),
+            offline: (0..shards)
+                .map(|_| Mutex::new(OfflineStore::new(cursors.clone())))
+                .collect(),
+        }
+    }
+
+    pub fn shards(&self) -> usize {
+        self.connected.len()
+    }
+
+    fn shard(&self, user_id: u64) -> usize {
+        (user_id % self.connected.len() as u64) as usize
+    }
+
+    // user_id 所在分片的连接表，同一个分片里还有其他用户，不要拿着它再锁别的分片
+    pub fn connected(&self, user_id: u64) -> MutexGuard<'_, Registry<T>> {
+        self.connected[self.shard(user_id)].lock().unwrap()
+    }
+
+    pub fn offline(&self, user_id: u64) -> MutexGuard<'_, OfflineStore> {
+        self.offline[self.shard(user_id)].lock().unwrap()
+    }
+
+    pub fn is_online(&self, user_id: u64) -> bool {
+        self.connected(user_id).is_online(user_id)
+    }
+
+    pub fn online_users(&self) -> Vec<u64> {
+        self.connected
+            .iter()
+            .flat_map(|shard| shard.lock().unwrap().users())
+            .collect()
+    }
+
+    // 连接数和 f 对每个连接的返回值之和
+    pub fn connections(&self, f: impl Fn(&T) -> usize) -> (usize, usize) {
+        self.connected.iter().fold((0, 0), |(n, sum), shard| {
+            let shard = shard.lock().unwrap();
+            (n + shard.len(), sum + shard.values().map(&f).sum::<usize>())
+        })
+    }
+
+    // 和 Registry::switch 一样，from 和 to 不在一个分片的话按分片的顺序加锁，不会死锁
+    pub fn switch(&self, from: u64, to: u64, addr: SocketAddr) -> Option<(bool, bool)> {
+        let (a, b) = (self.shard(from), self.shard(to));
+        if a == b {
+            return self.connected[a].lock().unwrap().switch(from, to, addr);
+        }
+        let (mut src, mut dst) = if a < b {
+            let src = self.connected[a].lock().unwrap();
+            (src, self.connected[b].lock().unwrap())
+        } else {
+            let dst = self.connected[b].lock().unwrap();
+            (self.connected[a].lock().unwrap(), dst)
+        };
+        let client = src.remove(from, addr)?;
+        Some((!src.is_online(from), dst.insert(to, addr, client)))
+    }
+
+    // (有离线消息的用户数, 离线消息总数)
+    pub fn offline_stats(&self) -> (usize, usize) {
+        self.offline.iter().fold((0, 0), |(users, msgs), shard| {
+            let (u, m) = shard.lock().unwrap().stats();
+            (users + u, msgs + m)
+        })
+    }
+
+    pub fn expire(&self, now: i64) {
+        for shard in &self.offline {
+            shard.lock().unwrap().expire(now);
+        }
+    }
+
+    // 关闭服务端时把所有离线消息存到 path, 返回存了多少条
+    pub fn save_offline(&self, path: &Path) -> io::Result<usize> {
+        let msgs: Vec<OfflineMsg> = self
+            .offline
+            .iter()
+            .flat_map(|shard| shard.lock().unwrap().dump())
+            .collect();
+        storage::save(path, &msgs)?;
+        Ok(msgs.len())
+    }
+
+    // 启动时放回去，cursor 会重新分配，然后删掉文件，否则下次启动会再放一次
+    pub fn load_offline(&self, path: &Path) -> io::Result<usize> {
+        let msgs = storage::load::<OfflineMsg>(path)?;
+        let n = msgs.len();
+        for OfflineMsg { user_id, msg } in msgs {
+            self.offline(user_id).push(user_id, msg)?;
+        }
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::msg::Msg2C;
+    use crate::testutil::TempDir;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_server_state() {
+        let cursors = Arc::new(IdAllocator::new(None, false).unwrap());
+        let state = ServerState::new(4, cursors);
+        assert!(state.connected(1).insert(1, addr(1000), 10));
+        assert!(state.connected(2).insert(2, addr(1001), 20));
+        assert!(!state.connected(2).insert(2, addr(1002), 30));
+        assert!(state.connected(5).insert(5, addr(1003), 50)); // 和 1 在同一个分片
+        assert!(state.connected(1).clients(5).is_some());
+        assert!(state.connected(2).clients(5).is_none());
+
+        let mut users = state.online_users();
+        users.sort();
+        assert_eq!(users, vec![1, 2, 5]);
+        assert_eq!(state.connections(|&c| c), (4, 110));
+
+        // 跨分片和同一个分片里切换
+        assert_eq!(state.switch(2, 3, addr(1001)), Some((false, true)));
+        assert_eq!(state.switch(5, 1, addr(1003)), Some((true, false)));
+        assert_eq!(state.switch(5, 1, addr(1003)), None);
+        assert!(!state.is_online(5));
+        assert_eq!(state.connected(3).clients(3).unwrap().len(), 1);
+
+        state.offline(1).push(1, Msg2C::Ok).unwrap();
+        state.offline(2).push(2, Msg2C::Ok).unwrap();
+        state.offline(2).push(2, Msg2C::Quit).unwrap();
+        assert_eq!(state.offline_stats(), (2, 3));
+    }
+
+    #[test]
+    fn test_save_offline() {
+        let dir = TempDir::new();
+        let path = dir.file("state");
+        let cursors = Arc::new(IdAllocator::new(None, false).unwrap());
+        let state: ServerState<()> = ServerState::new(4, cursors.clone());
+        state.offline(1).push(1, Msg2C::Ok).unwrap();
+        state.offline(2).push(2, Msg2C::Ok).unwrap();
+        state.offline(2).push(2, Msg2C::Quit).unwrap();
+        assert_eq!(state.save_offline(&path).unwrap(), 3);
+        drop(state); // 模拟重启
+
+        let state: ServerState<()> = ServerState::new(2, cursors);
+        assert_eq!(state.load_offline(&path).unwrap(), 3);
+        let msgs: Vec<Msg2C> = state
+            .offline(2)
+            .page(2, 0, 10)
+            .0
+            .into_iter()
+            .map(|(_, m)| m)
+            .collect();
+        assert_eq!(msgs, vec![Msg2C::Ok, Msg2C::Quit]);
+        assert!(!path.exists());
+        assert_eq!(state.load_offline(&path).unwrap(), 0);
+    }
+}
@@ -0,0 +1,92 @@
+use crate::error::Error;
+use crate::msg::FrameMsg;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Cursor, Write};
+use std::path::Path;
+
+// 简单的文件持久化：记录按 FrameMsg 的格式依次写在文件里
+
+pub fn load<T: FrameMsg>(path: &Path) -> io::Result<Vec<T>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut src = Cursor::new(&bytes[..]);
+    let mut res = vec![];
+    while (src.position() as usize) < bytes.len() {
+        let start = src.position();
+        match T::check(&mut src) {
+            Ok(_) => {
+                src.set_position(start);
+                res.push(T::parse(&mut src));
+            }
+            Err(Error::Incomplete) => {
+                // 最后一条没写完（写的时候进程崩溃了），截掉，否则后面 append 的都读不出来
+                OpenOptions::new().write(true).open(path)?.set_len(start)?;
+                break;
+            }
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        }
+    }
+    Ok(res)
+}
+
+pub fn append<T: FrameMsg>(path: &Path, item: &T) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&item.to_bytes())
+}
+
+pub fn save<T: FrameMsg>(path: &Path, items: &[T]) -> io::Result<()> {
+    let mut bytes = vec![];
+    for item in items {
+        bytes.extend(item.to_bytes());
+    }
+    write_atomic(path, &bytes)
+}
+
+// 先写临时文件再 rename, 不会留下写了一半的文件
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::msg::Record;
+    use crate::testutil::TempDir;
+
+    fn record(msg_id: u64) -> Record {
+        Record {
+            msg_id,
+            from: 1,
+            to: 2,
+            ts: 0,
+            msg: "hello".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_storage() {
+        let dir = TempDir::new();
+        let path = dir.file("storage");
+        assert_eq!(load::<Record>(&path).unwrap(), vec![]);
+
+        save(&path, &[record(1), record(2)]).unwrap();
+        append(&path, &record(3)).unwrap();
+        assert_eq!(
+            load::<Record>(&path).unwrap(),
+            vec![record(1), record(2), record(3)]
+        );
+
+        // 模拟写了一半就崩溃
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&record(4).to_bytes()[..10]).unwrap();
+        assert_eq!(load::<Record>(&path).unwrap().len(), 3);
+        append(&path, &record(5)).unwrap();
+        assert_eq!(load::<Record>(&path).unwrap().last(), Some(&record(5)));
+    }
+}
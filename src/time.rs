@@ -1,9 +1,29 @@
-use chrono::Local;
+use chrono::{Local, NaiveDate, TimeZone};
 
 pub fn get_current_timestamp() -> i64 {
     Local::now().timestamp()
 }
 
+pub fn get_current_timestamp_millis() -> i64 {
+    Local::now().timestamp_millis()
+}
+
+// "2024-01-31" -> 当天 0 点的时间戳
+pub fn parse_date(s: &str) -> Option<i64> {
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+    let dt = Local
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0)?)
+        .earliest()?;
+    Some(dt.timestamp())
+}
+
+pub fn format_timestamp(ts: i64) -> String {
+    match Local.timestamp_opt(ts, 0).single() {
+        Some(dt) => dt.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => ts.to_string(),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -11,6 +31,14 @@ mod test {
     use chrono::prelude::*;
     use utils::dbgt;
 
+    #[test]
+    fn test_parse_date() {
+        let ts = parse_date("2024-01-31").unwrap();
+        assert_eq!(format_timestamp(ts), "2024-01-31 00:00:00");
+        assert_eq!(parse_date("2024-02-01").unwrap() - ts, 86400);
+        assert!(parse_date("2024-13-01").is_none());
+    }
+
     #[test]
     fn test_time() {
         println!("Now time is {:?}", std::time::SystemTime::now());